@@ -0,0 +1,157 @@
+use crate::{gid::Gid, protection_domain::ProtectionDomain};
+use rdma_sys::{ibv_ah, ibv_ah_attr, ibv_create_ah, ibv_destroy_ah};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+    ptr::NonNull,
+    sync::Arc,
+};
+
+/// Bytes in front of every UD receive buffer that the device fills with the GRH
+pub const GRH_LEN: usize = 40;
+
+/// A RoCE v2 IPv4 header fills the tail of the GRH space
+const IPV4_HEADER_LEN: usize = 20;
+
+/// Everything a peer needs to send datagrams to a UD QP
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct DatagramEndpoint {
+    pub qp_num: u32,
+    pub lid: u16,
+    pub gid: Gid,
+    pub qkey: u32,
+}
+
+/// The route to a remote UD QP
+pub struct AddressHandle {
+    _pd: Arc<ProtectionDomain>,
+    inner_ah: NonNull<ibv_ah>,
+    remote: DatagramEndpoint,
+}
+
+impl AddressHandle {
+    pub(crate) fn as_ptr(&self) -> *mut ibv_ah {
+        self.inner_ah.as_ptr()
+    }
+
+    pub(crate) fn create(pd: &Arc<ProtectionDomain>, remote: DatagramEndpoint) -> io::Result<Self> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_ah_attr>() };
+        attr.dlid = remote.lid;
+        attr.sl = 0;
+        attr.src_path_bits = 0;
+        attr.is_global = 1;
+        attr.port_num = pd.ctx.port_num();
+        attr.grh.dgid = remote.gid.into();
+        attr.grh.hop_limit = 0xff;
+        attr.grh.sgid_index = pd.ctx.gid_index();
+        let inner_ah = NonNull::new(unsafe { ibv_create_ah(pd.as_ptr(), &mut attr) })
+            .ok_or_else(io::Error::last_os_error)?;
+        Ok(Self {
+            _pd: pd.clone(),
+            inner_ah,
+            remote,
+        })
+    }
+
+    pub fn remote(&self) -> DatagramEndpoint {
+        self.remote
+    }
+}
+
+impl Drop for AddressHandle {
+    fn drop(&mut self) {
+        let errno = unsafe { ibv_destroy_ah(self.as_ptr()) };
+        assert_eq!(errno, 0);
+    }
+}
+
+unsafe impl Send for AddressHandle {}
+
+unsafe impl Sync for AddressHandle {}
+
+/// The global route header the device places in front of a received datagram
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Grh {
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub hop_limit: u8,
+    pub sgid: Gid,
+    pub dgid: Gid,
+}
+
+impl Grh {
+    /// For RoCE v2 over IPv4 the last 20 bytes hold the IPv4 header instead, its
+    /// addresses come out as IPv4-mapped GIDs
+    pub(crate) fn parse(buf: &[u8]) -> Self {
+        let ipv4 = &buf[GRH_LEN - IPV4_HEADER_LEN..GRH_LEN];
+        if buf[0] >> 4 != 6 && ipv4[0] >> 4 == 4 {
+            let addr = |range: std::ops::Range<usize>| -> Gid {
+                let octets = <[u8; 4]>::try_from(&ipv4[range]).unwrap();
+                IpAddr::from(Ipv4Addr::from(octets)).into()
+            };
+            return Self {
+                traffic_class: ipv4[1],
+                flow_label: 0,
+                hop_limit: ipv4[8],
+                sgid: addr(12..16),
+                dgid: addr(16..20),
+            };
+        }
+        let word = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let gid = |range: std::ops::Range<usize>| -> Gid {
+            <[u8; 16]>::try_from(&buf[range]).unwrap().into()
+        };
+        Self {
+            traffic_class: (word >> 20) as u8,
+            flow_label: word & 0xf_ffff,
+            hop_limit: buf[7],
+            sgid: gid(8..24),
+            dgid: gid(24..40),
+        }
+    }
+}
+
+/// Where a datagram came from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DatagramSource {
+    pub qp_num: u32,
+    pub grh: Option<Grh>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ipv6() {
+        let mut buf = [0_u8; GRH_LEN];
+        buf[0..4].copy_from_slice(&0x6a1_2345_u32.to_be_bytes());
+        buf[7] = 64;
+        buf[8..24].copy_from_slice(&[1; 16]);
+        buf[24..40].copy_from_slice(&[2; 16]);
+        let grh = Grh::parse(&buf);
+        assert_eq!(grh.traffic_class, 0x6a);
+        assert_eq!(grh.flow_label, 0x1_2345);
+        assert_eq!(grh.hop_limit, 64);
+        assert_eq!(grh.sgid, Gid::from([1; 16]));
+        assert_eq!(grh.dgid, Gid::from([2; 16]));
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        let mut buf = [0_u8; GRH_LEN];
+        let ipv4 = &mut buf[GRH_LEN - IPV4_HEADER_LEN..];
+        ipv4[0] = 0x45;
+        ipv4[1] = 0x10;
+        ipv4[8] = 63;
+        ipv4[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ipv4[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        let grh = Grh::parse(&buf);
+        assert_eq!(grh.traffic_class, 0x10);
+        assert_eq!(grh.flow_label, 0);
+        assert_eq!(grh.hop_limit, 63);
+        assert_eq!(grh.sgid, Gid::from(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(grh.dgid, Gid::from(IpAddr::from([10, 0, 0, 2])));
+    }
+}
@@ -1,52 +1,79 @@
 use crate::{
+    handshake::HandshakeMessage,
     memory_region::{LocalMemoryRegion, MemoryRegionToken, RemoteMemoryRegion},
     mr_allocator::MRAllocator,
-    queue_pair::QueuePair,
+    queue_pair::{
+        OperationTimeout, QueuePair, QueuePairOp, QueuePairOps, Received, TypedQueuePair,
+    },
+    shared_receive_queue::SrqMessage,
 };
 use rand::Rng;
+use rdma_sys::ibv_access_flags;
 use serde::{Deserialize, Serialize};
 use std::{
     alloc::Layout,
     any::Any,
     collections::HashMap,
     io::{self, Cursor},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 use tokio::{
     sync::{
         mpsc::{channel, Receiver, Sender},
-        oneshot, Mutex,
+        oneshot, Mutex, Notify,
     },
     task::JoinHandle,
+    time::Instant,
 };
 use tracing::debug;
 
 pub struct Agent {
     inner: Arc<AgentInner>,
     mr_recv: Mutex<Receiver<io::Result<Arc<dyn Any + Send + Sync>>>>,
-    data_recv: Mutex<Receiver<LocalMemoryRegion>>,
+    data_recv: Mutex<Receiver<(LocalMemoryRegion, Option<u32>)>>,
+    imm_recv: Mutex<Receiver<(u32, usize)>>,
     _handle: JoinHandle<io::Result<()>>,
 }
 
 impl Agent {
-    pub fn new(qp: Arc<QueuePair>, allocator: Arc<MRAllocator>) -> Self {
+    pub fn new(
+        qp: Arc<QueuePair>,
+        qps: Arc<RwLock<Vec<Arc<QueuePair>>>>,
+        allocator: Arc<MRAllocator>,
+        access: ibv_access_flags,
+        request_timeout: Option<Duration>,
+    ) -> Self {
+        let srq_recv = qp.srq().map(|srq| srq.subscribe(qp.qp_num()));
         let response_waits = Arc::new(Mutex::new(HashMap::new()));
         let mr_own = Arc::new(Mutex::new(HashMap::new()));
         let (mr_send, mr_recv) = channel(1024);
         let (data_send, data_recv) = channel(1024);
+        let (imm_send, imm_recv) = channel(1024);
         let mr_recv = Mutex::new(mr_recv);
         let data_recv = Mutex::new(data_recv);
+        let imm_recv = Mutex::new(imm_recv);
         let inner = Arc::new(AgentInner {
             qp,
+            qps,
+            access,
             response_waits,
             mr_own,
             allocator,
+            request_timeout,
+            closed: AtomicBool::new(false),
+            remote_closed: AtomicBool::new(false),
+            remote_close_notify: Notify::new(),
         });
-        let _handle = AgentThread::run(inner.clone(), mr_send, data_send);
+        let _handle = AgentThread::run(inner.clone(), mr_send, data_send, imm_send, srq_recv);
         Self {
             inner,
             mr_recv,
             data_recv,
+            imm_recv,
             _handle,
         }
     }
@@ -73,15 +100,84 @@ impl Agent {
             request_id: RequestId::new(),
             kind: RequestKind::SendMR(SendMRRequest { kind: request }),
         };
-        let _response = self.inner.send_request(request).await.unwrap();
+        let _response = self.inner.send_request(request).await?;
+        Ok(())
+    }
+
+    pub async fn open_qp(&self) -> io::Result<usize> {
+        let qp = self.inner.qp.new_sibling(self.inner.access)?;
+        let local = HandshakeMessage::new(qp.qp(), MESSAGE_MAX_SIZE);
+        let request = Request {
+            request_id: RequestId::new(),
+            kind: RequestKind::OpenQP(OpenQPRequest { handshake: local }),
+        };
+        let remote =
+            if let ResponseKind::OpenQP(response) = self.inner.send_request(request).await? {
+                response
+                    .handshake
+                    .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "peer answered an open qp request with another kind of response",
+                ));
+            };
+        let qp = qp.handshake(&local.negotiate(&remote)?)?.into_inner();
+        Ok(self.inner.add_qp(qp))
+    }
+
+    /// Resets `qp` and connects it again to the same peer QP
+    pub async fn recover_qp(&self, qp: Arc<QueuePair>) -> io::Result<()> {
+        let remote = qp.remote().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("qp {} was never connected", qp.qp_num()),
+            )
+        })?;
+        // The QP is only reset once the peer agreed, a refused recovery leaves it untouched
+        let local = HandshakeMessage::new(&qp, MESSAGE_MAX_SIZE);
+        let request = Request {
+            request_id: RequestId::new(),
+            kind: RequestKind::RecoverQP(RecoverQPRequest {
+                qp_num: remote.qp_num(),
+                handshake: local,
+            }),
+        };
+        let remote =
+            if let ResponseKind::RecoverQP(response) = self.inner.send_request(request).await? {
+                response
+                    .handshake
+                    .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "peer answered a recover qp request with another kind of response",
+                ));
+            };
+        let negotiated = local.negotiate(&remote)?;
+        let qp = TypedQueuePair::reset(qp)?
+            .into_init(self.inner.access)?
+            .handshake(&negotiated)?
+            .into_inner();
+        qp.watch_async_events();
         Ok(())
     }
 
     pub async fn receive_mr(&self) -> io::Result<Arc<dyn Any + Send + Sync>> {
-        self.mr_recv.lock().await.recv().await.unwrap()
+        self.mr_recv
+            .lock()
+            .await
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(remote_closed_error()))
     }
 
     pub async fn send(&self, lm: &LocalMemoryRegion) -> io::Result<()> {
+        self.send_with_imm(lm, None).await
+    }
+
+    /// Every chunk of `lm` carries `imm` out of band
+    pub async fn send_with_imm(&self, lm: &LocalMemoryRegion, imm: Option<u32>) -> io::Result<()> {
         let mut start = 0;
         let lm_len = lm.length();
         while start < lm_len {
@@ -92,73 +188,177 @@ impl Agent {
             };
             let response = self
                 .inner
-                .send_request_append_data(request, vec![&lm.slice(start..end).unwrap()])
-                .await
-                .unwrap();
-            if let ResponseKind::SendData(response) = response {
-                if response.status > 0 {
-                    todo!()
-                }
-            } else {
-                panic!();
-            }
+                .send_request_append_data(request, vec![&lm.slice(start..end).unwrap()], imm)
+                .await?;
+            check_send_data(response)?;
             start = end;
         }
         Ok(())
     }
 
-    pub async fn receive(&self) -> LocalMemoryRegion {
-        self.data_recv.lock().await.recv().await.unwrap()
+    /// Sends `data` without a registered buffer, it has to fit the inline threshold
+    pub async fn send_inline(&self, data: &[u8]) -> io::Result<()> {
+        let request = Request {
+            request_id: RequestId::new(),
+            kind: RequestKind::SendData(SendDataRequest { len: data.len() }),
+        };
+        let response = self.inner.send_request_inline_data(request, data).await?;
+        check_send_data(response)
+    }
+
+    pub async fn receive(&self) -> io::Result<LocalMemoryRegion> {
+        self.receive_with_imm().await.map(|(lm, _)| lm)
+    }
+
+    pub async fn receive_with_imm(&self) -> io::Result<(LocalMemoryRegion, Option<u32>)> {
+        self.data_recv
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(remote_closed_error)
+    }
+
+    /// The immediate and length of the next write with immediate that landed on this side
+    pub async fn receive_write_imm(&self) -> io::Result<(u32, usize)> {
+        self.imm_recv
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(remote_closed_error)
+    }
+
+    pub async fn close(&self) -> io::Result<()> {
+        let ans = if self.inner.remote_closed.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            let request = Request {
+                request_id: RequestId::new(),
+                kind: RequestKind::Disconnect,
+            };
+            match self.inner.send_request(request).await {
+                Err(_) if self.inner.remote_closed.load(Ordering::Acquire) => Ok(()),
+                ans => ans.map(|_| ()),
+            }
+        };
+        self.inner.closed.store(true, Ordering::Release);
+        self._handle.abort();
+        self.inner.fail_response_waits(&closed_error()).await;
+        self.inner.mr_own.lock().await.clear();
+        ans
+    }
+
+    /// Stops the agent without telling the peer, pending requests fail
+    pub async fn stop(&self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self._handle.abort();
+        self.inner.fail_response_waits(&closed_error()).await;
+    }
+
+    pub fn is_remote_closed(&self) -> bool {
+        self.inner.remote_closed.load(Ordering::Acquire)
+    }
+
+    pub async fn remote_closed(&self) {
+        loop {
+            let notified = self.inner.remote_close_notify.notified();
+            if self.is_remote_closed() {
+                return;
+            }
+            notified.await;
+        }
     }
 }
 
 struct AgentThread {
     inner: Arc<AgentInner>,
     mr_send: Sender<io::Result<Arc<dyn Any + Send + Sync>>>,
-    data_send: Sender<LocalMemoryRegion>,
+    data_send: Sender<(LocalMemoryRegion, Option<u32>)>,
+    imm_send: Sender<(u32, usize)>,
 }
 
 impl AgentThread {
     fn run(
         inner: Arc<AgentInner>,
         mr_send: Sender<io::Result<Arc<dyn Any + Send + Sync>>>,
-        data_send: Sender<LocalMemoryRegion>,
+        data_send: Sender<(LocalMemoryRegion, Option<u32>)>,
+        imm_send: Sender<(u32, usize)>,
+        srq_recv: Option<Receiver<SrqMessage>>,
     ) -> JoinHandle<io::Result<()>> {
         let agent = Arc::new(Self {
             inner,
             mr_send,
             data_send,
+            imm_send,
         });
-        tokio::spawn(agent.main())
+        tokio::spawn(agent.main(srq_recv))
     }
 
-    async fn main(self: Arc<Self>) -> io::Result<()> {
-        let mut buf = self
-            .inner
-            .allocator
-            .alloc(Layout::new::<[u8; MESSAGE_MAX_SIZE]>())
-            .unwrap();
+    /// With a SRQ the messages of this QP come in buffers posted by the SRQ
+    async fn receive_message(
+        &self,
+        srq_recv: &mut Option<Receiver<SrqMessage>>,
+        spare: Option<LocalMemoryRegion>,
+    ) -> io::Result<(LocalMemoryRegion, Received)> {
+        if let Some(srq_recv) = srq_recv {
+            return srq_recv.recv().await.unwrap_or_else(|| Err(closed_error()));
+        }
+        let buf = match spare {
+            Some(buf) => buf,
+            None => self
+                .inner
+                .allocator
+                .alloc(Layout::new::<[u8; MESSAGE_MAX_SIZE]>())?,
+        };
+        let received = self.inner.qp.receive(&buf).await?;
+        Ok((buf, received))
+    }
+
+    async fn main(self: Arc<Self>, mut srq_recv: Option<Receiver<SrqMessage>>) -> io::Result<()> {
+        let mut spare = None;
         loop {
             debug!("receiving message");
-            let sz = self.inner.qp.receive(&buf).await.unwrap();
+            let (buf, received) = match self.receive_message(&mut srq_recv, spare.take()).await {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!("agent stopped receiving: {}", err);
+                    self.inner.fail_response_waits(&err).await;
+                    return Err(err);
+                }
+            };
+            // A write with immediate consumes a receive but carries no message
+            let (sz, imm) = match received {
+                Received::Send { len, imm } => (len, imm),
+                Received::WriteWithImm { len, imm } => {
+                    // Waits for room rather than losing the notification, the peer's writes
+                    // with immediate then wait for receives in turn
+                    if self.imm_send.send((imm, len)).await.is_err() {
+                        debug!("dropping write imm {}, the agent is gone", imm);
+                    }
+                    spare = Some(buf);
+                    continue;
+                }
+            };
             debug!("received message, size = {}", sz);
             let message = bincode::deserialize(&buf.as_slice()[0..sz]).unwrap();
             match message {
                 Message::Request(request) => match &request.kind {
+                    RequestKind::Disconnect => {
+                        self.handle_disconnect(request).await;
+                        return Ok(());
+                    }
                     RequestKind::SendData(_) => {
-                        tokio::spawn(self.clone().handle_send(request, buf));
-                        buf = self
-                            .inner
-                            .allocator
-                            .alloc(Layout::new::<[u8; MESSAGE_MAX_SIZE]>())
-                            .unwrap();
+                        tokio::spawn(self.clone().handle_send(request, buf, imm));
                     }
                     _ => {
                         tokio::spawn(self.clone().handle_request(request));
+                        spare = Some(buf);
                     }
                 },
                 Message::Response(response) => {
                     tokio::spawn(self.clone().handle_response(response));
+                    spare = Some(buf);
                 }
             };
         }
@@ -208,6 +408,20 @@ impl AgentThread {
                 }
                 ResponseKind::SendMR(SendMRResponse {})
             }
+            RequestKind::OpenQP(param) => {
+                let handshake = self
+                    .inner
+                    .accept_qp(param.handshake)
+                    .map_err(|e| e.to_string());
+                ResponseKind::OpenQP(OpenQPResponse { handshake })
+            }
+            RequestKind::RecoverQP(param) => {
+                let handshake = self
+                    .inner
+                    .reconnect_qp(param.qp_num, param.handshake)
+                    .map_err(|e| e.to_string());
+                ResponseKind::RecoverQP(RecoverQPResponse { handshake })
+            }
             RequestKind::ReceiveData => todo!(),
             _ => panic!(),
         };
@@ -219,6 +433,18 @@ impl AgentThread {
         debug!("handle request done");
     }
 
+    async fn handle_disconnect(&self, request: Request) {
+        debug!("remote disconnect");
+        self.inner.remote_closed.store(true, Ordering::Release);
+        let response = Response {
+            request_id: request.request_id,
+            kind: ResponseKind::Disconnect,
+        };
+        self.inner.send_response(response).await;
+        self.inner.response_waits.lock().await.clear();
+        self.inner.remote_close_notify.notify_waiters();
+    }
+
     async fn handle_response(self: Arc<Self>, response: Response) {
         debug!("handle response");
         let sender = self
@@ -226,20 +452,34 @@ impl AgentThread {
             .response_waits
             .lock()
             .await
-            .remove(&response.request_id)
-            .unwrap();
+            .remove(&response.request_id);
+        let sender = match sender {
+            Some(sender) => sender,
+            None => {
+                debug!(
+                    "dropping response to {:?}, it timed out",
+                    response.request_id
+                );
+                return;
+            }
+        };
         match sender.send(Ok(response.kind)) {
             Ok(_) => (),
             Err(_) => todo!(),
         }
     }
 
-    async fn handle_send(self: Arc<Self>, request: Request, buf: LocalMemoryRegion) {
+    async fn handle_send(
+        self: Arc<Self>,
+        request: Request,
+        buf: LocalMemoryRegion,
+        imm: Option<u32>,
+    ) {
         if let RequestKind::SendData(param) = request.kind {
             let buf = buf
                 .slice(*SEND_DATA_OFFSET..*SEND_DATA_OFFSET + param.len)
                 .unwrap();
-            self.data_send.send(buf).await.unwrap();
+            self.data_send.send((buf, imm)).await.unwrap();
             let response = Response {
                 request_id: request.request_id,
                 kind: ResponseKind::SendData(SendDataResponse { status: 0 }),
@@ -253,9 +493,16 @@ impl AgentThread {
 
 pub struct AgentInner {
     qp: Arc<QueuePair>,
+    qps: Arc<RwLock<Vec<Arc<QueuePair>>>>,
+    access: ibv_access_flags,
     response_waits: Arc<Mutex<ResponseWaitsMap>>,
     mr_own: Arc<Mutex<HashMap<MemoryRegionToken, Arc<LocalMemoryRegion>>>>,
     allocator: Arc<MRAllocator>,
+    /// How long a request waits for its response, unbounded if `None`
+    request_timeout: Option<Duration>,
+    closed: AtomicBool,
+    remote_closed: AtomicBool,
+    remote_close_notify: Notify,
 }
 
 impl AgentInner {
@@ -268,7 +515,7 @@ impl AgentInner {
             request_id: RequestId::new(),
             kind: RequestKind::AllocMR(request),
         };
-        let response = self.send_request(request).await.unwrap();
+        let response = self.send_request(request).await?;
         if let ResponseKind::AllocMR(response) = response {
             Ok(RemoteMemoryRegion::new_from_token(
                 response.token,
@@ -284,57 +531,229 @@ impl AgentInner {
             request_id: RequestId::new(),
             kind: RequestKind::ReleaseMR(ReleaseMRRequest { token }),
         };
-        let _response = self.send_request(request).await.unwrap();
+        let _response = self.send_request(request).await?;
         Ok(())
     }
 
+    fn accept_qp(&self, remote: HandshakeMessage) -> io::Result<HandshakeMessage> {
+        let qp = self.qp.new_sibling(self.access)?;
+        let local = HandshakeMessage::new(qp.qp(), MESSAGE_MAX_SIZE);
+        let qp = qp.handshake(&local.negotiate(&remote)?)?.into_inner();
+        let _ = self.add_qp(qp);
+        Ok(local)
+    }
+
+    /// The peer side of `Agent::recover_qp`
+    fn reconnect_qp(&self, qp_num: u32, remote: HandshakeMessage) -> io::Result<HandshakeMessage> {
+        let qp = self
+            .qps
+            .read()
+            .unwrap()
+            .iter()
+            .skip(1)
+            .find(|qp| qp.qp_num() == qp_num)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no qp {} to recover", qp_num),
+                )
+            })?;
+        let qp = TypedQueuePair::reset(qp)?.into_init(self.access)?;
+        let local = HandshakeMessage::new(qp.qp(), MESSAGE_MAX_SIZE);
+        let qp = qp.handshake(&local.negotiate(&remote)?)?.into_inner();
+        qp.watch_async_events();
+        Ok(local)
+    }
+
+    fn add_qp(&self, qp: Arc<QueuePair>) -> usize {
+        qp.watch_async_events();
+        let mut qps = self.qps.write().unwrap();
+        qps.push(qp);
+        qps.len() - 1
+    }
+
+    async fn fail_response_waits(&self, err: &io::Error) {
+        for (_, sender) in self.response_waits.lock().await.drain() {
+            let _ = sender.send(Err(io::Error::new(err.kind(), err.to_string())));
+        }
+    }
+
     async fn send_request(&self, request: Request) -> io::Result<ResponseKind> {
-        self.send_request_append_data(request, vec![]).await
+        self.send_request_append_data(request, vec![], None).await
     }
 
     async fn send_request_append_data(
         &self,
         request: Request,
         lm: Vec<&LocalMemoryRegion>,
+        imm: Option<u32>,
     ) -> io::Result<ResponseKind> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(closed_error());
+        }
+        if self.remote_closed.load(Ordering::Acquire) {
+            return Err(remote_closed_error());
+        }
+        let request_id = request.request_id;
+        let deadline = self.request_deadline();
         let (send, recv) = oneshot::channel();
-        self.response_waits
-            .lock()
-            .await
-            .insert(request.request_id, send);
+        self.response_waits.lock().await.insert(request_id, send);
+        let sent = self
+            .send_message(&Message::Request(request), lm, imm, deadline)
+            .await;
+        self.wait_response(request_id, sent, recv, deadline).await
+    }
+
+    fn request_deadline(&self) -> Option<Instant> {
+        self.request_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// A request that timed out is forgotten, its late response gets dropped
+    async fn wait_response(
+        &self,
+        request_id: RequestId,
+        sent: io::Result<()>,
+        mut recv: oneshot::Receiver<io::Result<ResponseKind>>,
+        deadline: Option<Instant>,
+    ) -> io::Result<ResponseKind> {
+        if let Err(err) = sent {
+            let _ = self.response_waits.lock().await.remove(&request_id);
+            return Err(err);
+        }
+        let response = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, &mut recv).await {
+                Ok(response) => response,
+                Err(_) => {
+                    if self
+                        .response_waits
+                        .lock()
+                        .await
+                        .remove(&request_id)
+                        .is_some()
+                    {
+                        let timeout = self.request_timeout.unwrap_or_default();
+                        return Err(OperationTimeout::AgentRequest(timeout).into());
+                    }
+                    // Already taken out by the agent thread, the response is on its way
+                    recv.await
+                }
+            },
+            None => recv.await,
+        };
+        response.map_err(|_| remote_closed_error())?
+    }
+
+    /// `data` goes inline right behind the request, the whole message has to fit the inline
+    /// threshold
+    async fn send_request_inline_data(
+        &self,
+        request: Request,
+        data: &[u8],
+    ) -> io::Result<ResponseKind> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(closed_error());
+        }
+        if self.remote_closed.load(Ordering::Acquire) {
+            return Err(remote_closed_error());
+        }
+        let request_id = request.request_id;
+        let mut bytes = bincode::serialize(&Message::Request(request)).unwrap();
+        bytes.extend_from_slice(data);
+        if bytes.len() > self.qp.inline_threshold() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes of data exceed the inline threshold {}",
+                    data.len(),
+                    self.qp.inline_threshold().saturating_sub(*SEND_DATA_OFFSET)
+                ),
+            ));
+        }
+        let deadline = self.request_deadline();
+        let (send, recv) = oneshot::channel();
+        self.response_waits.lock().await.insert(request_id, send);
+        let sent = with_deadline(self.qp.send_inline(&bytes), deadline).await;
+        self.wait_response(request_id, sent, recv, deadline).await
+    }
+
+    async fn send_response(&self, response: Response) {
+        let message = Message::Response(response);
+        if let Err(err) = self.send_message(&message, vec![], None, None).await {
+            debug!("failed to send response: {}", err);
+        }
+    }
+
+    /// Control messages small enough go inline and need no registered buffer
+    async fn send_message(
+        &self,
+        message: &Message,
+        lm: Vec<&LocalMemoryRegion>,
+        imm: Option<u32>,
+        deadline: Option<Instant>,
+    ) -> io::Result<()> {
+        let msz = bincode::serialized_size(message).unwrap() as usize;
+        if lm.is_empty() && imm.is_none() && msz <= self.qp.inline_threshold() {
+            let bytes = bincode::serialize(message).unwrap();
+            return with_deadline(self.qp.send_inline(&bytes), deadline).await;
+        }
         let mut buf = self
             .allocator
-            .alloc(Layout::new::<[u8; MESSAGE_MAX_SIZE]>())
-            .unwrap();
+            .alloc(Layout::new::<[u8; MESSAGE_MAX_SIZE]>())?;
         let cursor = Cursor::new(buf.as_mut_slice());
-        let message = Message::Request(request);
-        let msz = bincode::serialized_size(&message).unwrap() as usize;
-        bincode::serialize_into(cursor, &message).unwrap();
+        bincode::serialize_into(cursor, message).unwrap();
         let buf = buf.slice(0..msz).unwrap();
         let mut lms = vec![&buf];
         lms.extend(lm);
         let lms_len: usize = lms.iter().map(|lm| lm.length()).sum();
         assert!(lms_len <= MESSAGE_MAX_SIZE);
-        self.qp.send_sge(lms).await.unwrap();
-        let ans = recv.await.unwrap();
-        ans
+        match imm {
+            Some(imm) => with_deadline(self.qp.send_sge_with_imm(lms, imm), deadline).await,
+            None => with_deadline(self.qp.send_sge(lms), deadline).await,
+        }
     }
+}
 
-    async fn send_response(&self, response: Response) {
-        let mut buf = self
-            .allocator
-            .alloc(Layout::new::<[u8; MESSAGE_MAX_SIZE]>())
-            .unwrap();
-        let cursor = Cursor::new(buf.as_mut_slice());
-        let message = Message::Response(response);
-        let msz = bincode::serialized_size(&message).unwrap() as usize;
-        bincode::serialize_into(cursor, &message).unwrap();
-        let buf = buf.slice(0..msz).unwrap();
-        self.qp.send(&buf).await.unwrap();
+fn with_deadline<Op: QueuePairOp + Unpin>(
+    ops: QueuePairOps<Op>,
+    deadline: Option<Instant>,
+) -> QueuePairOps<Op> {
+    match deadline {
+        Some(deadline) => ops.with_deadline(deadline),
+        None => ops,
+    }
+}
+
+pub(crate) fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "rdma connection is closed")
+}
+
+/// Fails unless the peer took the data of a send request
+fn check_send_data(response: ResponseKind) -> io::Result<()> {
+    match response {
+        ResponseKind::SendData(response) if response.status > 0 => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "peer failed to take the sent data, status {}",
+                response.status
+            ),
+        )),
+        ResponseKind::SendData(_) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "peer answered a send request with another kind of response",
+        )),
     }
 }
 
-const MESSAGE_MAX_SIZE: usize = 4096;
+fn remote_closed_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "remote closed the rdma connection",
+    )
+}
+
+pub(crate) const MESSAGE_MAX_SIZE: usize = 4096;
 
 lazy_static! {
     static ref SEND_DATA_OFFSET: usize = {
@@ -401,6 +820,27 @@ struct ReceiveMRRequest {}
 #[derive(Serialize, Deserialize)]
 struct ReceiveMRResponse {}
 
+#[derive(Serialize, Deserialize)]
+struct OpenQPRequest {
+    handshake: HandshakeMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenQPResponse {
+    handshake: Result<HandshakeMessage, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecoverQPRequest {
+    qp_num: u32,
+    handshake: HandshakeMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecoverQPResponse {
+    handshake: Result<HandshakeMessage, String>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct SendDataRequest {
     len: usize,
@@ -419,6 +859,9 @@ enum RequestKind {
     ReceiveMR,
     SendData(SendDataRequest),
     ReceiveData,
+    OpenQP(OpenQPRequest),
+    RecoverQP(RecoverQPRequest),
+    Disconnect,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -435,6 +878,9 @@ enum ResponseKind {
     ReceiveMR,
     SendData(SendDataResponse),
     ReceiveData,
+    OpenQP(OpenQPResponse),
+    RecoverQP(RecoverQPResponse),
+    Disconnect,
 }
 
 #[derive(Serialize, Deserialize)]
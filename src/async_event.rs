@@ -0,0 +1,167 @@
+use crate::context::Context;
+use futures::stream::{self, BoxStream};
+use rdma_sys::{ibv_ack_async_event, ibv_async_event, ibv_event_type, ibv_get_async_event};
+use std::{
+    io,
+    os::unix::prelude::{FromRawFd, OwnedFd, RawFd},
+    sync::Weak,
+};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+pub(crate) const ASYNC_EVENT_CHANNEL_SIZE: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AsyncEvent {
+    CqError { cq_handle: u32 },
+    QpFatal { qp_num: u32 },
+    QpRequestError { qp_num: u32 },
+    QpAccessError { qp_num: u32 },
+    CommEstablished { qp_num: u32 },
+    SqDrained { qp_num: u32 },
+    PathMigrated { qp_num: u32 },
+    PathMigrationError { qp_num: u32 },
+    QpLastWqeReached { qp_num: u32 },
+    SrqError { srq_handle: u32 },
+    SrqLimitReached { srq_handle: u32 },
+    DeviceFatal,
+    PortActive { port_num: u8 },
+    PortError { port_num: u8 },
+    LidChange { port_num: u8 },
+    PkeyChange { port_num: u8 },
+    SmChange { port_num: u8 },
+    ClientReregister { port_num: u8 },
+    GidChange { port_num: u8 },
+    Unknown { event_type: u32 },
+}
+
+impl AsyncEvent {
+    /// Must be called before the event is acked, the element may be released afterwards
+    unsafe fn from_raw(event: &ibv_async_event) -> Self {
+        let qp_num = || (*event.element.qp).qp_num;
+        let cq_handle = || (*event.element.cq).handle;
+        let srq_handle = || (*event.element.srq).handle;
+        let port_num = || event.element.port_num as u8;
+        match event.event_type {
+            ibv_event_type::IBV_EVENT_CQ_ERR => Self::CqError {
+                cq_handle: cq_handle(),
+            },
+            ibv_event_type::IBV_EVENT_QP_FATAL => Self::QpFatal { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_QP_REQ_ERR => Self::QpRequestError { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_QP_ACCESS_ERR => Self::QpAccessError { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_COMM_EST => Self::CommEstablished { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_SQ_DRAINED => Self::SqDrained { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_PATH_MIG => Self::PathMigrated { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_PATH_MIG_ERR => Self::PathMigrationError { qp_num: qp_num() },
+            ibv_event_type::IBV_EVENT_QP_LAST_WQE_REACHED => {
+                Self::QpLastWqeReached { qp_num: qp_num() }
+            }
+            ibv_event_type::IBV_EVENT_SRQ_ERR => Self::SrqError {
+                srq_handle: srq_handle(),
+            },
+            ibv_event_type::IBV_EVENT_SRQ_LIMIT_REACHED => Self::SrqLimitReached {
+                srq_handle: srq_handle(),
+            },
+            ibv_event_type::IBV_EVENT_DEVICE_FATAL => Self::DeviceFatal,
+            ibv_event_type::IBV_EVENT_PORT_ACTIVE => Self::PortActive {
+                port_num: port_num(),
+            },
+            ibv_event_type::IBV_EVENT_PORT_ERR => Self::PortError {
+                port_num: port_num(),
+            },
+            ibv_event_type::IBV_EVENT_LID_CHANGE => Self::LidChange {
+                port_num: port_num(),
+            },
+            ibv_event_type::IBV_EVENT_PKEY_CHANGE => Self::PkeyChange {
+                port_num: port_num(),
+            },
+            ibv_event_type::IBV_EVENT_SM_CHANGE => Self::SmChange {
+                port_num: port_num(),
+            },
+            ibv_event_type::IBV_EVENT_CLIENT_REREGISTER => Self::ClientReregister {
+                port_num: port_num(),
+            },
+            ibv_event_type::IBV_EVENT_GID_CHANGE => Self::GidChange {
+                port_num: port_num(),
+            },
+            event_type => Self::Unknown { event_type },
+        }
+    }
+}
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads the async events of `ctx` and broadcasts them until the context is dropped
+pub(crate) fn start_poller(
+    ctx: Weak<Context>,
+    fd: RawFd,
+    sender: broadcast::Sender<AsyncEvent>,
+) -> JoinHandle<()> {
+    // An aborted task drops its `AsyncFd` later, possibly after the device closed `fd` and the
+    // number got reused. A duplicate is only deregistered and closed by the task itself
+    let fd = match unsafe { libc::dup(fd) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    };
+    tokio::spawn(async move {
+        let async_fd = match fd.and_then(AsyncFd::new) {
+            Ok(async_fd) => async_fd,
+            Err(err) => {
+                debug!("failed to register async event fd: {}", err);
+                return;
+            }
+        };
+        loop {
+            let mut guard = match async_fd.readable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    debug!("async event fd failed: {}", err);
+                    return;
+                }
+            };
+            let ctx = match ctx.upgrade() {
+                Some(ctx) => ctx,
+                None => return,
+            };
+            loop {
+                let mut raw = unsafe { std::mem::zeroed::<ibv_async_event>() };
+                // Nonblocking fd, fails with EAGAIN once all pending events are read
+                if unsafe { ibv_get_async_event(ctx.as_ptr(), &mut raw) } != 0 {
+                    guard.clear_ready();
+                    break;
+                }
+                let event = unsafe { AsyncEvent::from_raw(&raw) };
+                unsafe { ibv_ack_async_event(&mut raw) };
+                debug!("async event {:?}", event);
+                // No subscribers is fine, the event is simply dropped
+                let _ = sender.send(event);
+            }
+        }
+    })
+}
+
+pub(crate) fn event_stream(
+    receiver: broadcast::Receiver<AsyncEvent>,
+) -> BoxStream<'static, AsyncEvent> {
+    Box::pin(stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("async event stream lagged, {} events missed", missed)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }))
+}
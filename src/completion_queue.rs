@@ -4,13 +4,18 @@ use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use rand::Rng;
 use rdma_sys::{
-    ibv_cq, ibv_create_cq, ibv_destroy_cq, ibv_poll_cq, ibv_req_notify_cq, ibv_wc, ibv_wc_status,
+    ibv_cq, ibv_create_cq, ibv_destroy_cq, ibv_poll_cq, ibv_req_notify_cq, ibv_resize_cq, ibv_wc,
+    ibv_wc_flags, ibv_wc_opcode, ibv_wc_status,
 };
 use std::{
     cmp::Ordering,
     fmt::Debug,
     io, mem,
     ptr::{self, NonNull},
+    sync::{
+        atomic::{self, AtomicU64},
+        Mutex,
+    },
     time::{SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
@@ -18,6 +23,10 @@ use thiserror::Error;
 pub struct CompletionQueue {
     ec: Option<EventChannel>,
     inner_cq: NonNull<ibv_cq>,
+    /// Entries the work queues completing here may use at once
+    reserved: Mutex<u32>,
+    /// Completions polled so far
+    polled: AtomicU64,
 }
 
 impl CompletionQueue {
@@ -40,7 +49,54 @@ impl CompletionQueue {
             )
         })
         .ok_or(io::ErrorKind::Other)?;
-        Ok(CompletionQueue { ec, inner_cq })
+        Ok(CompletionQueue {
+            ec,
+            inner_cq,
+            reserved: Mutex::new(0),
+            polled: AtomicU64::new(0),
+        })
+    }
+
+    pub fn handle(&self) -> u32 {
+        unsafe { (*self.as_ptr()).handle }
+    }
+
+    /// The number of entries, the device may round the requested size up
+    pub fn capacity(&self) -> u32 {
+        unsafe { (*self.as_ptr()).cqe as u32 }
+    }
+
+    /// Makes room for `n` more outstanding completions, growing the CQ when it could overrun.
+    /// An overrun moves every QP on the CQ to the error state
+    pub fn reserve(&self, n: u32, max_cqe: u32) -> io::Result<()> {
+        let mut reserved = self.reserved.lock().unwrap();
+        let wanted = reserved.saturating_add(n);
+        if wanted > max_cqe {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cq needs {} entries for its work queues, the device supports {}",
+                    wanted, max_cqe
+                ),
+            ));
+        }
+        if wanted > self.capacity() {
+            let errno = unsafe { ibv_resize_cq(self.as_ptr(), wanted as _) };
+            if errno != 0 {
+                let err = io::Error::from_raw_os_error(errno);
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("failed to grow cq to {} entries: {}", wanted, err),
+                ));
+            }
+        }
+        *reserved = wanted;
+        Ok(())
+    }
+
+    pub fn release(&self, n: u32) {
+        let mut reserved = self.reserved.lock().unwrap();
+        *reserved = reserved.saturating_sub(n);
     }
 
     pub fn req_notify(&self, solicited_only: bool) -> io::Result<()> {
@@ -68,6 +124,9 @@ impl CompletionQueue {
                     ans.remove(poll_res);
                 }
                 assert_eq!(ans.len(), poll_res);
+                let _ = self
+                    .polled
+                    .fetch_add(poll_res as u64, atomic::Ordering::Relaxed);
                 Ok(ans)
             }
             Ordering::Less => Err(io::Error::new(io::ErrorKind::WouldBlock, "")),
@@ -82,6 +141,11 @@ impl CompletionQueue {
             .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, ""))
     }
 
+    /// Completions taken from the CQ so far
+    pub fn polled(&self) -> u64 {
+        self.polled.load(atomic::Ordering::Relaxed)
+    }
+
     pub fn event_channel(&self) -> &EventChannel {
         self.ec.as_ref().unwrap()
     }
@@ -112,6 +176,88 @@ impl WorkCompletion {
         WorkRequestId(self.inner_wc.wr_id)
     }
 
+    pub fn qp_num(&self) -> u32 {
+        self.inner_wc.qp_num
+    }
+
+    /// Only valid for successful completions, unknown opcodes give `None`
+    pub fn opcode(&self) -> Option<WcOpcode> {
+        WcOpcode::from_u32(self.inner_wc.opcode)
+    }
+
+    /// The transferred length, also reported for failed completions
+    pub fn byte_len(&self) -> usize {
+        self.inner_wc.byte_len as usize
+    }
+
+    pub fn wc_flags(&self) -> ibv_wc_flags {
+        ibv_wc_flags(self.inner_wc.wc_flags)
+    }
+
+    pub fn vendor_err(&self) -> u32 {
+        self.inner_wc.vendor_err
+    }
+
+    pub fn pkey_index(&self) -> u16 {
+        self.inner_wc.pkey_index
+    }
+
+    /// The source LID of a received message
+    pub fn slid(&self) -> u16 {
+        self.inner_wc.slid
+    }
+
+    /// The service level of a received message
+    pub fn sl(&self) -> u8 {
+        self.inner_wc.sl
+    }
+
+    pub fn dlid_path_bits(&self) -> u8 {
+        self.inner_wc.dlid_path_bits
+    }
+
+    /// The sending QP of a datagram
+    pub fn src_qp(&self) -> u32 {
+        self.inner_wc.src_qp
+    }
+
+    /// Whether the first 40 bytes of a datagram receive buffer hold a GRH
+    pub fn has_grh(&self) -> bool {
+        self.inner_wc.wc_flags & ibv_wc_flags::IBV_WC_GRH.0 != 0
+    }
+
+    /// In host byte order, the wire carries it big endian
+    pub fn imm_data(&self) -> Option<u32> {
+        (self.inner_wc.wc_flags & ibv_wc_flags::IBV_WC_WITH_IMM.0 != 0)
+            .then(|| u32::from_be(unsafe { self.inner_wc.__bindgen_anon_1.imm_data }))
+    }
+
+    /// The rkey a send with invalidate revoked
+    pub fn invalidated_rkey(&self) -> Option<u32> {
+        (self.inner_wc.wc_flags & ibv_wc_flags::IBV_WC_WITH_INV.0 != 0)
+            .then(|| unsafe { self.inner_wc.__bindgen_anon_1.invalidated_rkey })
+    }
+
+    /// The device verified the IP checksum of a received packet
+    pub fn ip_csum_ok(&self) -> bool {
+        self.inner_wc.wc_flags & ibv_wc_flags::IBV_WC_IP_CSUM_OK.0 != 0
+    }
+
+    /// The receive was consumed by a remote write with immediate instead of a send
+    pub fn is_recv_rdma_with_imm(&self) -> bool {
+        self.opcode() == Some(WcOpcode::RecvRdmaWithImm)
+    }
+
+    /// Stands in for an unsignaled WR, the device reports it only when it fails
+    pub(crate) fn implicit(&self, wr_id: WorkRequestId, byte_len: u32) -> Self {
+        let mut ans = self.clone();
+        ans.inner_wc.wr_id = wr_id.into();
+        ans.inner_wc.status = ibv_wc_status::IBV_WC_SUCCESS;
+        ans.inner_wc.byte_len = byte_len;
+        ans.inner_wc.wc_flags = 0;
+        ans
+    }
+
     pub fn err(&self) -> Result<usize, WCError> {
         if self.inner_wc.status == ibv_wc_status::IBV_WC_SUCCESS {
             Ok(self.inner_wc.byte_len as usize)
@@ -125,6 +271,19 @@ impl Debug for WorkCompletion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("WorkCompletion")
             .field("wr_id", &self.wr_id())
+            .field("error", &self.err().err())
+            .field("opcode", &self.opcode())
+            .field("byte_len", &self.byte_len())
+            .field("imm_data", &self.imm_data())
+            .field("invalidated_rkey", &self.invalidated_rkey())
+            .field("qp_num", &self.qp_num())
+            .field("src_qp", &self.src_qp())
+            .field("wc_flags", &format_args!("{:#x}", self.inner_wc.wc_flags))
+            .field("pkey_index", &self.pkey_index())
+            .field("slid", &self.slid())
+            .field("sl", &self.sl())
+            .field("dlid_path_bits", &self.dlid_path_bits())
+            .field("vendor_err", &self.vendor_err())
             .finish()
     }
 }
@@ -145,6 +304,27 @@ impl Clone for WorkCompletion {
     }
 }
 
+/// What a completion reports, receive side opcodes have the `Recv` prefix
+#[derive(Clone, Copy, PartialEq, Eq, Debug, FromPrimitive)]
+pub enum WcOpcode {
+    Send = 0,
+    RdmaWrite = 1,
+    RdmaRead = 2,
+    CompSwap = 3,
+    FetchAdd = 4,
+    BindMw = 5,
+    LocalInv = 6,
+    Tso = 7,
+    Recv = 128,
+    RecvRdmaWithImm = 129,
+}
+
+impl WcOpcode {
+    pub fn is_recv(self) -> bool {
+        self as u32 & ibv_wc_opcode::IBV_WC_RECV != 0
+    }
+}
+
 #[derive(Error, Debug, FromPrimitive)]
 pub enum WCError {
     #[error("Local Length Error: this happens if a Work Request that was posted in a local Send Queue contains a message that is greater than the maximum message size that is supported by the RDMA device port that should send the message or an Atomic operation which its size is different than 8 bytes was sent. This also may happen if a Work Request that was posted in a local Receive Queue isn't big enough for holding the incoming message or if the incoming message size if greater the maximum message size supported by the RDMA device port that received the message.")]
@@ -220,8 +400,38 @@ impl Default for WorkRequestId {
     }
 }
 
+impl From<u64> for WorkRequestId {
+    fn from(wr_id: u64) -> Self {
+        Self(wr_id)
+    }
+}
+
 impl From<WorkRequestId> for u64 {
     fn from(wr_id: WorkRequestId) -> Self {
         wr_id.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::completion_queue::{WcOpcode, WorkCompletion, WorkRequestId};
+    use rdma_sys::{ibv_wc_flags, ibv_wc_opcode};
+
+    #[test]
+    fn test1() {
+        let mut wc = WorkCompletion::default();
+        wc.inner_wc.opcode = ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM;
+        wc.inner_wc.wc_flags = ibv_wc_flags::IBV_WC_WITH_IMM.0 | ibv_wc_flags::IBV_WC_GRH.0;
+        wc.inner_wc.__bindgen_anon_1.imm_data = 7_u32.to_be();
+        wc.inner_wc.byte_len = 16;
+        assert_eq!(wc.opcode(), Some(WcOpcode::RecvRdmaWithImm));
+        assert!(wc.opcode().unwrap().is_recv());
+        assert_eq!(wc.imm_data(), Some(7));
+        assert!(wc.has_grh());
+        assert_eq!(wc.invalidated_rkey(), None);
+        assert!(format!("{:?}", wc).contains("RecvRdmaWithImm"));
+        let implicit = wc.implicit(WorkRequestId::from(1), 8);
+        assert_eq!(implicit.err().unwrap(), 8);
+        assert_eq!(implicit.imm_data(), None);
+    }
+}
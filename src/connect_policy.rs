@@ -0,0 +1,120 @@
+use futures::Future;
+use std::{fmt::Display, io, time::Duration};
+use thiserror::Error;
+use tracing::debug;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectPolicy {
+    pub deadline: Option<Duration>,
+    pub retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub step_timeout: Duration,
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        Self {
+            deadline: None,
+            retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            step_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConnectPolicy {
+    pub(crate) async fn with_deadline<T, F: Future<Output = io::Result<T>>>(
+        &self,
+        fut: F,
+    ) -> io::Result<T> {
+        match self.deadline {
+            Some(deadline) => tokio::time::timeout(deadline, fut)
+                .await
+                .map_err(|_| ConnectTimeout::Deadline(deadline))?,
+            None => fut.await,
+        }
+    }
+
+    pub(crate) async fn step<T, F: Future<Output = io::Result<T>>>(
+        &self,
+        step: ConnectStep,
+        fut: F,
+    ) -> io::Result<T> {
+        tokio::time::timeout(self.step_timeout, fut)
+            .await
+            .map_err(|_| ConnectTimeout::Step(step, self.step_timeout))?
+    }
+
+    pub(crate) async fn retry<T, F, Fut>(&self, step: ConnectStep, mut attempt: F) -> io::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let err = match attempt().await {
+                Ok(ans) => return Ok(ans),
+                Err(err) => err,
+            };
+            if tries > self.retries || !is_retryable(&err) {
+                return Err(err);
+            }
+            debug!("{} failed: {}, retry in {:?}", step, err, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+fn is_retryable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotFound
+            | io::ErrorKind::TimedOut
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectStep {
+    Connect,
+    Accept,
+    ExchangeEndpoint,
+    ResolveRoute,
+    CmConnect,
+    CmAccept,
+}
+
+impl Display for ConnectStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let step = match self {
+            ConnectStep::Connect => "connecting to the peer",
+            ConnectStep::Accept => "accepting a connection",
+            ConnectStep::ExchangeEndpoint => "exchanging endpoints",
+            ConnectStep::ResolveRoute => "resolving address and route",
+            ConnectStep::CmConnect => "waiting for the rdma cm connect response",
+            ConnectStep::CmAccept => "waiting for the rdma cm connection to be established",
+        };
+        f.write_str(step)
+    }
+}
+
+#[derive(Error, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectTimeout {
+    #[error("connect deadline of {0:?} exceeded")]
+    Deadline(Duration),
+    #[error("timed out after {1:?} while {0}")]
+    Step(ConnectStep, Duration),
+}
+
+impl From<ConnectTimeout> for io::Error {
+    fn from(e: ConnectTimeout) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
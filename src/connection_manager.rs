@@ -0,0 +1,381 @@
+use crate::queue_pair::QueuePairConfig;
+use libc::c_void;
+use rdma_sys::{
+    ibv_context, ibv_get_device_name, ibv_qp_attr, ibv_qp_state, rdma_accept, rdma_ack_cm_event,
+    rdma_bind_addr, rdma_cm_event, rdma_cm_event_type, rdma_cm_id, rdma_conn_param, rdma_connect,
+    rdma_create_event_channel, rdma_create_id, rdma_destroy_event_channel, rdma_destroy_id,
+    rdma_disconnect, rdma_establish, rdma_event_channel, rdma_get_cm_event, rdma_init_qp_attr,
+    rdma_listen, rdma_migrate_id, rdma_port_space, rdma_reject, rdma_resolve_addr,
+    rdma_resolve_route,
+};
+use std::{
+    ffi::CStr,
+    io, mem,
+    net::SocketAddr,
+    os::unix::prelude::{AsRawFd, RawFd},
+    ptr::{self, NonNull},
+    sync::Arc,
+};
+use tokio::io::unix::AsyncFd;
+use tracing::debug;
+
+const IBV_TRANSPORT_IWARP: i32 = 1;
+
+pub struct CmEventChannel {
+    inner_ec: NonNull<rdma_event_channel>,
+    async_fd: AsyncFd<RawFd>,
+}
+
+impl CmEventChannel {
+    pub(crate) fn as_ptr(&self) -> *mut rdma_event_channel {
+        self.inner_ec.as_ptr()
+    }
+
+    pub fn new() -> io::Result<Self> {
+        let inner_ec = NonNull::new(unsafe { rdma_create_event_channel() })
+            .ok_or_else(io::Error::last_os_error)?;
+        let fd = unsafe { inner_ec.as_ref() }.fd;
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { rdma_destroy_event_channel(inner_ec.as_ptr()) };
+            return Err(err);
+        }
+        let async_fd = match AsyncFd::new(fd) {
+            Ok(async_fd) => async_fd,
+            Err(err) => {
+                unsafe { rdma_destroy_event_channel(inner_ec.as_ptr()) };
+                return Err(err);
+            }
+        };
+        Ok(Self { inner_ec, async_fd })
+    }
+
+    async fn get_event(&self) -> io::Result<CmEvent> {
+        loop {
+            let mut guard = self.async_fd.readable().await?;
+            let mut event = ptr::null_mut::<rdma_cm_event>();
+            if unsafe { rdma_get_cm_event(self.as_ptr(), &mut event) } != 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    guard.clear_ready();
+                    continue;
+                }
+                return Err(err);
+            }
+            let cm_event = unsafe {
+                CmEvent {
+                    event: (*event).event,
+                    status: (*event).status,
+                    id: (*event).id,
+                }
+            };
+            unsafe { rdma_ack_cm_event(event) };
+            return Ok(cm_event);
+        }
+    }
+}
+
+unsafe impl Sync for CmEventChannel {}
+
+unsafe impl Send for CmEventChannel {}
+
+impl Drop for CmEventChannel {
+    fn drop(&mut self) {
+        unsafe { rdma_destroy_event_channel(self.as_ptr()) };
+    }
+}
+
+impl AsRawFd for CmEventChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        unsafe { self.inner_ec.as_ref() }.fd
+    }
+}
+
+struct CmEvent {
+    event: rdma_cm_event_type::Type,
+    status: i32,
+    id: *mut rdma_cm_id,
+}
+
+impl CmEvent {
+    fn expect(self, event: rdma_cm_event_type::Type) -> io::Result<Self> {
+        if self.event == event && self.status == 0 {
+            Ok(self)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "unexpected rdma cm event {}, status {}, expecting event {}",
+                    self.event, self.status, event
+                ),
+            ))
+        }
+    }
+}
+
+pub struct CmId {
+    ec: Arc<CmEventChannel>,
+    inner_id: NonNull<rdma_cm_id>,
+}
+
+impl CmId {
+    pub(crate) fn as_ptr(&self) -> *mut rdma_cm_id {
+        self.inner_id.as_ptr()
+    }
+
+    pub fn new(ec: Arc<CmEventChannel>) -> io::Result<Self> {
+        let mut inner_id = ptr::null_mut::<rdma_cm_id>();
+        let errno = unsafe {
+            rdma_create_id(
+                ec.as_ptr(),
+                &mut inner_id,
+                ptr::null_mut::<c_void>(),
+                rdma_port_space::RDMA_PS_TCP,
+            )
+        };
+        if errno != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inner_id = NonNull::new(inner_id).ok_or(io::ErrorKind::Other)?;
+        Ok(Self { ec, inner_id })
+    }
+
+    /// Moves a connection request onto its own event channel, a request that can not be
+    /// taken over is rejected and destroyed
+    fn from_event(inner_id: *mut rdma_cm_id) -> io::Result<Self> {
+        let inner_id = NonNull::new(inner_id).ok_or(io::ErrorKind::Other)?;
+        let discard = |err: io::Error| {
+            if unsafe { rdma_reject(inner_id.as_ptr(), ptr::null(), 0) } != 0 {
+                debug!(
+                    "failed to reject rdma cm request: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            unsafe { rdma_destroy_id(inner_id.as_ptr()) };
+            err
+        };
+        let ec = Arc::new(CmEventChannel::new().map_err(discard)?);
+        if unsafe { rdma_migrate_id(inner_id.as_ptr(), ec.as_ptr()) } != 0 {
+            return Err(discard(io::Error::last_os_error()));
+        }
+        Ok(Self { ec, inner_id })
+    }
+
+    /// The context librdmacm opened for the device this id is bound to
+    pub(crate) fn verbs(&self) -> io::Result<NonNull<ibv_context>> {
+        NonNull::new(unsafe { (*self.as_ptr()).verbs }).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "rdma cm id is not bound to a device",
+            )
+        })
+    }
+
+    pub(crate) fn port_num(&self) -> u8 {
+        unsafe { (*self.as_ptr()).port_num }
+    }
+
+    pub fn device_name(&self) -> io::Result<String> {
+        let verbs = self.verbs()?.as_ptr();
+        let name = unsafe { ibv_get_device_name((*verbs).device) };
+        assert!(!name.is_null());
+        Ok(unsafe { CStr::from_ptr(name) }
+            .to_str()
+            .unwrap()
+            .to_string())
+    }
+
+    fn is_iwarp(&self) -> bool {
+        let verbs = unsafe { (*self.as_ptr()).verbs };
+        !verbs.is_null()
+            && unsafe { (*(*verbs).device).transport_type } as i32 == IBV_TRANSPORT_IWARP
+    }
+
+    pub(crate) fn init_qp_attr(&self, state: ibv_qp_state::Type) -> io::Result<(ibv_qp_attr, i32)> {
+        if state != ibv_qp_state::IBV_QPS_INIT && self.is_iwarp() {
+            // iWARP connections are moved to RTR and RTS by the kernel iw_cm
+            return Ok((unsafe { mem::zeroed() }, 0));
+        }
+        let mut attr = unsafe { mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = state;
+        let mut mask = 0;
+        if unsafe { rdma_init_qp_attr(self.as_ptr(), &mut attr, &mut mask) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((attr, mask))
+    }
+
+    pub async fn resolve(&self, addr: SocketAddr, timeout_ms: i32) -> io::Result<()> {
+        let mut dst = to_sockaddr(&addr);
+        let errno = unsafe {
+            rdma_resolve_addr(
+                self.as_ptr(),
+                ptr::null_mut(),
+                &mut dst as *mut _ as *mut _,
+                timeout_ms,
+            )
+        };
+        if errno != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.ec
+            .get_event()
+            .await?
+            .expect(rdma_cm_event_type::RDMA_CM_EVENT_ADDR_RESOLVED)?;
+        debug!("rdma cm address resolved");
+        if unsafe { rdma_resolve_route(self.as_ptr(), timeout_ms) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.ec
+            .get_event()
+            .await?
+            .expect(rdma_cm_event_type::RDMA_CM_EVENT_ROUTE_RESOLVED)?;
+        debug!("rdma cm route resolved");
+        Ok(())
+    }
+
+    pub async fn connect(&self, qp_num: u32, config: &QueuePairConfig) -> io::Result<()> {
+        let mut param = conn_param(qp_num, config);
+        if unsafe { rdma_connect(self.as_ptr(), &mut param) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let event = self.ec.get_event().await?;
+        // Only iWARP reports `ESTABLISHED` directly, because the QP is owned by us
+        if event.event != rdma_cm_event_type::RDMA_CM_EVENT_ESTABLISHED {
+            event.expect(rdma_cm_event_type::RDMA_CM_EVENT_CONNECT_RESPONSE)?;
+        }
+        Ok(())
+    }
+
+    pub fn establish(&self) -> io::Result<()> {
+        if self.is_iwarp() {
+            return Ok(());
+        }
+        if unsafe { rdma_establish(self.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Refuses a connection request, the peer gets a `REJECTED` event instead of a timeout
+    pub fn reject(&self) {
+        if unsafe { rdma_reject(self.as_ptr(), ptr::null(), 0) } != 0 {
+            debug!(
+                "failed to reject rdma cm request: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    pub fn disconnect(&self) {
+        unsafe { rdma_disconnect(self.as_ptr()) };
+    }
+
+    pub async fn accept(&self, qp_num: u32, config: &QueuePairConfig) -> io::Result<()> {
+        let mut param = conn_param(qp_num, config);
+        if unsafe { rdma_accept(self.as_ptr(), &mut param) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.ec
+            .get_event()
+            .await?
+            .expect(rdma_cm_event_type::RDMA_CM_EVENT_ESTABLISHED)?;
+        Ok(())
+    }
+}
+
+unsafe impl Sync for CmId {}
+
+unsafe impl Send for CmId {}
+
+impl Drop for CmId {
+    fn drop(&mut self) {
+        self.disconnect();
+        if unsafe { rdma_destroy_id(self.as_ptr()) } != 0 {
+            debug!(
+                "failed to destroy rdma cm id: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+pub struct CmListener {
+    id: CmId,
+}
+
+impl CmListener {
+    pub fn bind(addr: SocketAddr, backlog: i32) -> io::Result<Self> {
+        let id = CmId::new(Arc::new(CmEventChannel::new()?))?;
+        let mut src = to_sockaddr(&addr);
+        if unsafe { rdma_bind_addr(id.as_ptr(), &mut src as *mut _ as *mut _) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { rdma_listen(id.as_ptr(), backlog) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { id })
+    }
+
+    pub fn device_name(&self) -> io::Result<String> {
+        self.id.device_name()
+    }
+
+    pub(crate) fn id(&self) -> &CmId {
+        &self.id
+    }
+
+    pub async fn get_request(&self) -> io::Result<CmId> {
+        loop {
+            let event = self.id.ec.get_event().await?;
+            if event.event == rdma_cm_event_type::RDMA_CM_EVENT_CONNECT_REQUEST {
+                return CmId::from_event(event.id);
+            }
+            // Events of requests that never got migrated still land here, they are acked already
+            debug!(
+                "skipping rdma cm event {}, status {} on listener",
+                event.event, event.status
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for CmListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CmListener")
+            .field("id", &self.id.inner_id)
+            .finish()
+    }
+}
+
+fn conn_param(qp_num: u32, config: &QueuePairConfig) -> rdma_conn_param {
+    let mut param = unsafe { mem::zeroed::<rdma_conn_param>() };
+    param.qp_num = qp_num;
+    param.responder_resources = config.max_rd_atomic;
+    param.initiator_depth = config.max_rd_atomic;
+    param.retry_count = config.retry_cnt;
+    param.rnr_retry_count = config.rnr_retry;
+    param
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> libc::sockaddr_storage {
+    let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as _;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as _;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+        }
+    }
+    storage
+}
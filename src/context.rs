@@ -1,17 +1,32 @@
 use crate::{
-    completion_queue::CompletionQueue, event_channel::EventChannel, gid::Gid,
+    async_event::{self, AsyncEvent, ASYNC_EVENT_CHANNEL_SIZE},
+    completion_queue::CompletionQueue,
+    device::{device_name, gid_table, query_port, DeviceInfo, DeviceList, GidType},
+    event_channel::EventChannel,
+    gid::{Gid, GidSelector},
     protection_domain::ProtectionDomain,
 };
-use rdma_sys::{
-    ibv_close_device, ibv_context, ibv_free_device_list, ibv_get_device_list, ibv_get_device_name,
-    ibv_open_device, ibv_port_attr, ibv_query_gid,
+use futures::stream::BoxStream;
+use rdma_sys::{ibv_close_device, ibv_context, ibv_open_device, ibv_port_attr, ibv_query_gid};
+use std::{
+    io,
+    ptr::NonNull,
+    sync::{Arc, Mutex},
 };
-use std::{ffi::CStr, io, ptr::NonNull, sync::Arc};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+pub(crate) const DEFAULT_PORT_NUM: u8 = 1;
 
 pub struct Context {
     pub inner_ctx: NonNull<ibv_context>,
     pub inner_port_attr: ibv_port_attr,
     pub gid: Gid,
+    port_num: u8,
+    gid_index: u8,
+    async_events: broadcast::Sender<AsyncEvent>,
+    async_poller: Mutex<Option<JoinHandle<()>>>,
+    /// Contexts of rdma cm ids belong to librdmacm, which closes them itself
+    owned: bool,
 }
 
 impl Context {
@@ -20,36 +35,74 @@ impl Context {
     }
 
     pub fn open(dev_name: Option<&str>) -> io::Result<Self> {
-        let mut num_devs: i32 = 0;
-        let dev_list_ptr = unsafe { ibv_get_device_list(&mut num_devs as *mut _) };
-        if dev_list_ptr.is_null() {
-            return Err(io::Error::last_os_error());
-        }
-        let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as usize) };
+        Self::open_port(dev_name, DEFAULT_PORT_NUM, GidSelector::default())
+    }
+
+    pub fn open_port(
+        dev_name: Option<&str>,
+        port_num: u8,
+        gid_selector: GidSelector,
+    ) -> io::Result<Self> {
+        let dev_list = DeviceList::get()?;
         let dev = if let Some(dev_name) = dev_name {
             dev_list
+                .devices()
                 .iter()
-                .find(|iter_dev| {
-                    let name = unsafe { ibv_get_device_name(**iter_dev) };
-                    assert!(!name.is_null());
-                    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap();
-                    dev_name.eq(name)
-                })
+                .find(|iter_dev| dev_name.eq(&device_name(**iter_dev)))
                 .ok_or(io::ErrorKind::NotFound)?
         } else {
-            dev_list.get(0).ok_or(io::ErrorKind::NotFound)?
+            dev_list.devices().get(0).ok_or(io::ErrorKind::NotFound)?
         };
         let inner_ctx =
             NonNull::new(unsafe { ibv_open_device(*dev) }).ok_or_else(io::Error::last_os_error)?;
-        unsafe { ibv_free_device_list(dev_list_ptr) };
-        let mut gid = Gid::default();
-        let errno = unsafe { ibv_query_gid(inner_ctx.as_ptr(), 1, 1, gid.as_mut()) };
-        if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
+        drop(dev_list);
+        let ans = Self::new(inner_ctx, port_num, gid_selector, true);
+        if ans.is_err() {
+            let _ = unsafe { ibv_close_device(inner_ctx.as_ptr()) };
         }
-        let mut inner_port_attr = unsafe { std::mem::zeroed() };
+        ans
+    }
+
+    /// Wraps the context librdmacm opened for a cm id, QPs created on it can be handed to the
+    /// cm as they are
+    pub(crate) fn from_verbs(
+        verbs: NonNull<ibv_context>,
+        port_num: u8,
+        gid_selector: GidSelector,
+    ) -> io::Result<Self> {
+        Self::new(verbs, port_num, gid_selector, false)
+    }
+
+    fn new(
+        inner_ctx: NonNull<ibv_context>,
+        port_num: u8,
+        gid_selector: GidSelector,
+        owned: bool,
+    ) -> io::Result<Self> {
+        async_event::set_nonblocking(unsafe { inner_ctx.as_ref() }.async_fd)?;
+        let inner_port_attr = query_port(inner_ctx.as_ptr(), port_num)?;
+        let gid_index = match gid_selector {
+            GidSelector::Index(index) => index,
+            GidSelector::RoceV2(ip) => {
+                let dev_name = device_name(unsafe { inner_ctx.as_ref() }.device);
+                gid_table(inner_ctx.as_ptr(), &dev_name, port_num, &inner_port_attr)
+                    .into_iter()
+                    .find(|entry| entry.gid_type == GidType::RoceV2 && entry.gid == ip.into())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "no RoCE v2 GID of {} on device {} port {}",
+                                ip, dev_name, port_num
+                            ),
+                        )
+                    })?
+                    .index
+            }
+        };
+        let mut gid = Gid::default();
         let errno =
-            unsafe { rdma_sys::___ibv_query_port(inner_ctx.as_ptr(), 1, &mut inner_port_attr) };
+            unsafe { ibv_query_gid(inner_ctx.as_ptr(), port_num, gid_index.into(), gid.as_mut()) };
         if errno != 0 {
             return Err(io::Error::from_raw_os_error(errno));
         }
@@ -57,9 +110,27 @@ impl Context {
             inner_ctx,
             inner_port_attr,
             gid,
+            port_num,
+            gid_index,
+            async_events: broadcast::channel(ASYNC_EVENT_CHANNEL_SIZE).0,
+            async_poller: Mutex::new(None),
+            owned,
         })
     }
 
+    pub fn async_events(self: &Arc<Self>) -> BoxStream<'static, AsyncEvent> {
+        let receiver = self.async_events.subscribe();
+        let mut poller = self.async_poller.lock().unwrap();
+        if poller.is_none() {
+            *poller = Some(async_event::start_poller(
+                Arc::downgrade(self),
+                unsafe { (*self.as_ptr()).async_fd },
+                self.async_events.clone(),
+            ));
+        }
+        async_event::event_stream(receiver)
+    }
+
     pub fn create_event_channel(self: &Arc<Self>) -> io::Result<EventChannel> {
         EventChannel::new(self.clone())
     }
@@ -76,6 +147,22 @@ impl Context {
         ProtectionDomain::create(self)
     }
 
+    pub fn dev_name(&self) -> String {
+        device_name(unsafe { (*self.as_ptr()).device })
+    }
+
+    pub fn device_info(&self) -> io::Result<DeviceInfo> {
+        DeviceInfo::query(self.as_ptr())
+    }
+
+    pub fn port_num(&self) -> u8 {
+        self.port_num
+    }
+
+    pub fn gid_index(&self) -> u8 {
+        self.gid_index
+    }
+
     pub fn get_lid(&self) -> u16 {
         self.inner_port_attr.lid
     }
@@ -87,8 +174,13 @@ impl Context {
 
 impl Drop for Context {
     fn drop(&mut self) {
-        let errno = unsafe { ibv_close_device(self.as_ptr()) };
-        assert_eq!(errno, 0);
+        if let Some(poller) = self.async_poller.lock().unwrap().take() {
+            poller.abort();
+        }
+        if self.owned {
+            let errno = unsafe { ibv_close_device(self.as_ptr()) };
+            assert_eq!(errno, 0);
+        }
     }
 }
 
@@ -134,4 +226,25 @@ mod tests {
     fn test3() {
         let ctx = Context::open(None).unwrap();
     }
+
+    #[test]
+    fn test4() {
+        let ctx = Context::open_port(None, 1, GidSelector::Index(1)).unwrap();
+        assert_eq!(ctx.gid_index(), 1);
+        let roce_v2 = ctx
+            .device_info()
+            .unwrap()
+            .port(1)
+            .unwrap()
+            .gids
+            .iter()
+            .find(|entry| entry.gid_type == GidType::RoceV2)
+            .copied();
+        if let Some(entry) = roce_v2 {
+            let ip = std::net::Ipv6Addr::from(*entry.gid.as_raw());
+            let ip = ip.to_ipv4().map_or(ip.into(), std::net::IpAddr::V4);
+            let ctx = Context::open_port(None, 1, GidSelector::RoceV2(ip)).unwrap();
+            assert_eq!(ctx.gid, entry.gid);
+        }
+    }
 }
@@ -0,0 +1,109 @@
+use crate::{
+    address_handle::{AddressHandle, DatagramEndpoint, DatagramSource},
+    context::Context,
+    event_listener::EventListener,
+    memory_region::LocalMemoryRegion,
+    mr_allocator::MRAllocator,
+    protection_domain::ProtectionDomain,
+    queue_pair::{QueuePair, QueuePairConfig, QueuePairType, TypedQueuePair},
+};
+use std::{alloc::Layout, io, sync::Arc};
+
+pub(crate) const DEFAULT_QKEY: u32 = 0x1111_1111;
+
+/// A connectionless UD endpoint, one QP talks to any number of peers
+pub struct Datagram {
+    pd: Arc<ProtectionDomain>,
+    allocator: Arc<MRAllocator>,
+    qp: Arc<QueuePair>,
+}
+
+impl Datagram {
+    pub(crate) fn new(
+        ctx: &Arc<Context>,
+        pd: &Arc<ProtectionDomain>,
+        allocator: Arc<MRAllocator>,
+        cq_size: u32,
+        config: QueuePairConfig,
+        qkey: u32,
+    ) -> io::Result<Self> {
+        let ec = ctx.create_event_channel()?;
+        let cq = Arc::new(ctx.create_completion_queue(cq_size, Some(ec))?);
+        let qp = pd
+            .create_queue_pair_builder()
+            .set_qp_type(QueuePairType::Ud)
+            .set_event_listener(Arc::new(EventListener::new(cq)))
+            .set_config(config)
+            .build()?;
+        let qp = TypedQueuePair::reset(Arc::new(qp))?
+            .into_init_ud(qkey)?
+            .into_rtr_ud()?
+            .into_rts_ud(0)?
+            .into_inner();
+        qp.watch_async_events();
+        Ok(Self {
+            pd: pd.clone(),
+            allocator,
+            qp,
+        })
+    }
+
+    /// Handed to peers so they can create an address handle for this endpoint
+    pub fn endpoint(&self) -> DatagramEndpoint {
+        self.qp.datagram_endpoint()
+    }
+
+    pub fn create_ah(&self, remote: DatagramEndpoint) -> io::Result<AddressHandle> {
+        AddressHandle::create(&self.pd, remote)
+    }
+
+    /// Datagrams can not be larger than the path MTU
+    pub fn max_message_size(&self) -> usize {
+        self.qp.path_mtu_bytes()
+    }
+
+    pub fn alloc_local_mr(&self, layout: Layout) -> io::Result<LocalMemoryRegion> {
+        self.allocator.alloc(layout)
+    }
+
+    pub async fn send_to(&self, ah: &AddressHandle, lm: &LocalMemoryRegion) -> io::Result<()> {
+        self.qp.send_to(ah, lm).await
+    }
+
+    /// The payload starts at `GRH_LEN` in `lm`, returns its length and the sender
+    pub async fn recv_from(&self, lm: &LocalMemoryRegion) -> io::Result<(usize, DatagramSource)> {
+        self.qp.recv_from(lm).await
+    }
+}
+
+impl Drop for Datagram {
+    fn drop(&mut self) {
+        let _ = self.qp.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::alloc::Layout;
+
+    #[tokio::test]
+    async fn fatal_event() -> io::Result<()> {
+        let datagram = RdmaBuilder::default().build_datagram()?;
+        let buf = datagram.alloc_local_mr(Layout::new::<[u8; GRH_LEN + 8]>())?;
+        let mut received = Box::pin(datagram.recv_from(&buf));
+        assert!(futures::poll!(&mut received).is_pending());
+        let qp_num = datagram.endpoint().qp_num;
+        datagram.qp.fail(AsyncEvent::QpFatal { qp_num });
+        // The pending receive ends with its flush completion, not with the failure itself
+        let err = received.await.err().unwrap();
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref::<WCError>()),
+            Some(WCError::WrFlushErr)
+        ));
+        assert_eq!(datagram.qp.abandoned_in_flight(), 0);
+        let err = datagram.recv_from(&buf).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        Ok(())
+    }
+}
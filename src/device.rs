@@ -0,0 +1,340 @@
+use crate::gid::Gid;
+use rdma_sys::{
+    ibv_atomic_cap, ibv_close_device, ibv_context, ibv_device, ibv_device_attr,
+    ibv_free_device_list, ibv_get_device_list, ibv_get_device_name, ibv_open_device, ibv_port_attr,
+    ibv_port_state, ibv_query_device, ibv_query_gid,
+};
+use std::{ffi::CStr, io, ptr::NonNull};
+
+const LINK_LAYER_INFINIBAND: u8 = 1;
+const LINK_LAYER_ETHERNET: u8 = 2;
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub attr: DeviceAttr,
+    pub ports: Vec<PortInfo>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceAttr {
+    pub fw_ver: String,
+    pub node_guid: u64,
+    pub vendor_id: u32,
+    pub vendor_part_id: u32,
+    pub hw_ver: u32,
+    pub max_mr_size: u64,
+    pub max_qp: u32,
+    pub max_qp_wr: u32,
+    pub max_sge: u32,
+    pub max_sge_rd: u32,
+    pub max_cq: u32,
+    pub max_cqe: u32,
+    pub max_mr: u32,
+    pub max_pd: u32,
+    pub max_qp_rd_atom: u32,
+    pub max_qp_init_rd_atom: u32,
+    pub max_srq: u32,
+    pub max_srq_wr: u32,
+    pub max_srq_sge: u32,
+    pub max_ah: u32,
+    pub atomic_cap: AtomicCap,
+    pub phys_port_cnt: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct PortInfo {
+    pub port_num: u8,
+    pub state: PortState,
+    pub link_layer: LinkLayer,
+    /// In bytes
+    pub max_mtu: u32,
+    /// In bytes
+    pub active_mtu: u32,
+    pub max_msg_size: u32,
+    pub lid: u16,
+    pub active_speed: u8,
+    pub active_width: u8,
+    pub gids: Vec<GidEntry>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GidEntry {
+    pub index: u8,
+    pub gid: Gid,
+    pub gid_type: GidType,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GidType {
+    IbRoceV1,
+    RoceV2,
+    Unknown,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AtomicCap {
+    None,
+    Hca,
+    Global,
+    Unknown(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PortState {
+    Nop,
+    Down,
+    Init,
+    Armed,
+    Active,
+    ActiveDefer,
+    Unknown(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LinkLayer {
+    Unspecified,
+    InfiniBand,
+    Ethernet,
+}
+
+pub fn devices() -> io::Result<Vec<DeviceInfo>> {
+    let list = DeviceList::get()?;
+    list.devices()
+        .iter()
+        .map(|dev| {
+            let ctx = NonNull::new(unsafe { ibv_open_device(*dev) })
+                .ok_or_else(io::Error::last_os_error)?;
+            let info = DeviceInfo::query(ctx.as_ptr());
+            unsafe { ibv_close_device(ctx.as_ptr()) };
+            info
+        })
+        .collect()
+}
+
+impl DeviceInfo {
+    pub(crate) fn query(ctx: *mut ibv_context) -> io::Result<Self> {
+        let name = device_name(unsafe { (*ctx).device });
+        let attr = DeviceAttr::query(ctx)?;
+        let ports = (1..=attr.phys_port_cnt)
+            .map(|port_num| PortInfo::query(ctx, port_num, &name))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { name, attr, ports })
+    }
+
+    pub fn port(&self, port_num: u8) -> Option<&PortInfo> {
+        self.ports.iter().find(|port| port.port_num == port_num)
+    }
+}
+
+impl DeviceAttr {
+    pub(crate) fn query(ctx: *mut ibv_context) -> io::Result<Self> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_device_attr>() };
+        let errno = unsafe { ibv_query_device(ctx, &mut attr) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        let atomic_cap = match attr.atomic_cap {
+            ibv_atomic_cap::IBV_ATOMIC_NONE => AtomicCap::None,
+            ibv_atomic_cap::IBV_ATOMIC_HCA => AtomicCap::Hca,
+            ibv_atomic_cap::IBV_ATOMIC_GLOB => AtomicCap::Global,
+            cap => AtomicCap::Unknown(cap),
+        };
+        Ok(Self {
+            fw_ver: unsafe { CStr::from_ptr(attr.fw_ver.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+            node_guid: u64::from_be(attr.node_guid),
+            vendor_id: attr.vendor_id,
+            vendor_part_id: attr.vendor_part_id,
+            hw_ver: attr.hw_ver,
+            max_mr_size: attr.max_mr_size,
+            max_qp: attr.max_qp as u32,
+            max_qp_wr: attr.max_qp_wr as u32,
+            max_sge: attr.max_sge as u32,
+            max_sge_rd: attr.max_sge_rd as u32,
+            max_cq: attr.max_cq as u32,
+            max_cqe: attr.max_cqe as u32,
+            max_mr: attr.max_mr as u32,
+            max_pd: attr.max_pd as u32,
+            max_qp_rd_atom: attr.max_qp_rd_atom as u32,
+            max_qp_init_rd_atom: attr.max_qp_init_rd_atom as u32,
+            max_srq: attr.max_srq as u32,
+            max_srq_wr: attr.max_srq_wr as u32,
+            max_srq_sge: attr.max_srq_sge as u32,
+            max_ah: attr.max_ah as u32,
+            atomic_cap,
+            phys_port_cnt: attr.phys_port_cnt,
+        })
+    }
+
+    pub fn supports_atomic(&self) -> bool {
+        matches!(self.atomic_cap, AtomicCap::Hca | AtomicCap::Global)
+    }
+}
+
+impl PortInfo {
+    fn query(ctx: *mut ibv_context, port_num: u8, dev_name: &str) -> io::Result<Self> {
+        let attr = query_port(ctx, port_num)?;
+        let state = match attr.state {
+            ibv_port_state::IBV_PORT_NOP => PortState::Nop,
+            ibv_port_state::IBV_PORT_DOWN => PortState::Down,
+            ibv_port_state::IBV_PORT_INIT => PortState::Init,
+            ibv_port_state::IBV_PORT_ARMED => PortState::Armed,
+            ibv_port_state::IBV_PORT_ACTIVE => PortState::Active,
+            ibv_port_state::IBV_PORT_ACTIVE_DEFER => PortState::ActiveDefer,
+            state => PortState::Unknown(state),
+        };
+        let link_layer = match attr.link_layer {
+            LINK_LAYER_INFINIBAND => LinkLayer::InfiniBand,
+            LINK_LAYER_ETHERNET => LinkLayer::Ethernet,
+            _ => LinkLayer::Unspecified,
+        };
+        let gids = gid_table(ctx, dev_name, port_num, &attr);
+        Ok(Self {
+            port_num,
+            state,
+            link_layer,
+            max_mtu: mtu_bytes(attr.max_mtu),
+            active_mtu: mtu_bytes(attr.active_mtu),
+            max_msg_size: attr.max_msg_sz,
+            lid: attr.lid,
+            active_speed: attr.active_speed,
+            active_width: attr.active_width,
+            gids,
+        })
+    }
+
+    /// Per lane rate times lane count, None if the port reports an unknown encoding
+    pub fn rate_gbps(&self) -> Option<f64> {
+        let lane = match self.active_speed {
+            1 => 2.5,
+            2 => 5.0,
+            4 | 8 => 10.0,
+            16 => 14.0,
+            32 => 25.0,
+            64 => 50.0,
+            128 => 100.0,
+            _ => return None,
+        };
+        let lanes = match self.active_width {
+            1 => 1.0,
+            2 => 4.0,
+            4 => 8.0,
+            8 => 12.0,
+            16 => 2.0,
+            _ => return None,
+        };
+        Some(lane * lanes)
+    }
+}
+
+pub(crate) fn query_port(ctx: *mut ibv_context, port_num: u8) -> io::Result<ibv_port_attr> {
+    let mut attr = unsafe { std::mem::zeroed::<ibv_port_attr>() };
+    let errno = unsafe { rdma_sys::___ibv_query_port(ctx, port_num, &mut attr) };
+    if errno != 0 {
+        return Err(io::Error::from_raw_os_error(errno));
+    }
+    Ok(attr)
+}
+
+pub(crate) fn gid_table(
+    ctx: *mut ibv_context,
+    dev_name: &str,
+    port_num: u8,
+    attr: &ibv_port_attr,
+) -> Vec<GidEntry> {
+    let mut gids = vec![];
+    // sgid_index is a u8, entries past 255 can not be used
+    for index in 0..attr.gid_tbl_len.clamp(0, 256) {
+        let mut gid = Gid::default();
+        // Unpopulated RoCE entries fail to query, they are simply not listed
+        if unsafe { ibv_query_gid(ctx, port_num, index, gid.as_mut()) } != 0
+            || gid == Gid::default()
+        {
+            continue;
+        }
+        gids.push(GidEntry {
+            index: index as u8,
+            gid,
+            gid_type: gid_type(dev_name, port_num, index),
+        });
+    }
+    gids
+}
+
+/// The verbs API does not report GID types, the kernel exposes them in sysfs
+fn gid_type(dev_name: &str, port_num: u8, index: i32) -> GidType {
+    let path = format!(
+        "/sys/class/infiniband/{}/ports/{}/gid_attrs/types/{}",
+        dev_name, port_num, index
+    );
+    match std::fs::read_to_string(path) {
+        Ok(gid_type) => match gid_type.trim() {
+            "IB/RoCE v1" => GidType::IbRoceV1,
+            "RoCE v2" => GidType::RoceV2,
+            _ => GidType::Unknown,
+        },
+        Err(_) => GidType::Unknown,
+    }
+}
+
+/// Converts an `ibv_mtu` to bytes
+pub(crate) fn mtu_bytes(mtu: u32) -> u32 {
+    if mtu == 0 {
+        0
+    } else {
+        128 << mtu
+    }
+}
+
+pub(crate) fn device_name(dev: *mut ibv_device) -> String {
+    let name = unsafe { ibv_get_device_name(dev) };
+    assert!(!name.is_null());
+    unsafe { CStr::from_ptr(name) }
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+pub(crate) struct DeviceList {
+    list: NonNull<*mut ibv_device>,
+    len: usize,
+}
+
+impl DeviceList {
+    pub(crate) fn get() -> io::Result<Self> {
+        let mut num_devs: i32 = 0;
+        let list = NonNull::new(unsafe { ibv_get_device_list(&mut num_devs as *mut _) })
+            .ok_or_else(io::Error::last_os_error)?;
+        Ok(Self {
+            list,
+            len: num_devs as usize,
+        })
+    }
+
+    pub(crate) fn devices(&self) -> &[*mut ibv_device] {
+        unsafe { std::slice::from_raw_parts(self.list.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for DeviceList {
+    fn drop(&mut self) {
+        unsafe { ibv_free_device_list(self.list.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{context::Context, device::devices};
+
+    #[test]
+    fn test1() {
+        let devs = devices().unwrap();
+        let ctx = Context::open(None).unwrap();
+        let info = ctx.device_info().unwrap();
+        assert_eq!(devs[0].name, info.name);
+        assert_eq!(info.ports.len(), info.attr.phys_port_cnt as usize);
+        assert!(!info.port(1).unwrap().gids.is_empty());
+    }
+}
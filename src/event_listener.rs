@@ -1,47 +1,78 @@
 use crate::completion_queue::{CompletionQueue, WorkCompletion, WorkRequestId};
 use lockfree_cuckoohash::{pin, LockFreeCuckooHash};
-use std::{os::unix::prelude::AsRawFd, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    os::unix::prelude::AsRawFd,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
 use tokio::{io::unix::AsyncFd, sync::mpsc};
 
 /// Provided by the requester and used by the manager task to send
 /// the command response back to the requester.
 type Responder = mpsc::Sender<WorkCompletion>;
 type ReqMap = Arc<LockFreeCuckooHash<WorkRequestId, Responder>>;
+/// Unsignaled WRs and their lengths, keyed by the signaled WR posted after them
+type CoverMap = Arc<Mutex<HashMap<WorkRequestId, Vec<(WorkRequestId, u32)>>>>;
 pub struct EventListener {
     pub cq: Arc<CompletionQueue>,
     req_map: ReqMap,
-    _poller_handle: tokio::task::JoinHandle<()>,
+    covers: CoverMap,
+    poller_handle: tokio::task::JoinHandle<()>,
 }
 
 impl EventListener {
     pub fn new(cq: Arc<CompletionQueue>) -> EventListener {
         let req_map = Arc::new(LockFreeCuckooHash::new());
-        let req_map_move = req_map.clone();
+        let covers = Arc::new(Mutex::new(HashMap::new()));
         Self {
+            poller_handle: Self::start(cq.clone(), req_map.clone(), covers.clone()),
             req_map,
-            _poller_handle: Self::start(cq.clone(), req_map_move),
+            covers,
             cq,
         }
     }
 
-    pub fn start(cq: Arc<CompletionQueue>, req_map: ReqMap) -> tokio::task::JoinHandle<()> {
+    pub fn start(
+        cq: Arc<CompletionQueue>,
+        req_map: ReqMap,
+        covers: CoverMap,
+    ) -> tokio::task::JoinHandle<()> {
         tokio::task::spawn(async move {
             let async_fd = AsyncFd::new(cq.event_channel().as_raw_fd()).unwrap();
             loop {
                 async_fd.readable().await.unwrap().clear_ready();
                 cq.req_notify(false).unwrap();
                 while let Ok(wc) = cq.poll_single() {
-                    req_map
+                    let covered = covers.lock().unwrap().remove(&wc.wr_id());
+                    // Covered WRs that failed got their own completion before this one
+                    for (wr_id, len) in covered.into_iter().flatten() {
+                        if let Some(responder) = req_map.remove_with_guard(&wr_id, &pin()) {
+                            let _ = responder.clone().try_send(wc.implicit(wr_id, len));
+                        }
+                    }
+                    // The requester may have gone away, e.g. a flushed receive of a closed agent
+                    let _ = req_map
                         .remove_with_guard(&wc.wr_id(), &pin())
                         .unwrap()
                         .clone()
-                        .try_send(wc)
-                        .unwrap();
+                        .try_send(wc);
                 }
             }
         })
     }
 
+    /// Completes the unsignaled WRs in `covered` once `signaled` completes
+    pub fn cover(&self, signaled: WorkRequestId, covered: Vec<(WorkRequestId, u32)>) {
+        self.covers.lock().unwrap().insert(signaled, covered);
+    }
+
+    /// Drops the cover of a signaled WR the device rejected
+    pub fn uncover(&self, signaled: WorkRequestId) {
+        self.covers.lock().unwrap().remove(&signaled);
+    }
+
     pub fn register(&self) -> (WorkRequestId, mpsc::Receiver<WorkCompletion>) {
         let (tx, rx) = mpsc::channel(2);
         let mut wr_id = WorkRequestId::new();
@@ -53,4 +84,133 @@ impl EventListener {
         }
         (wr_id, rx)
     }
+
+    /// Forgets a registered WR that never made it to the device
+    pub fn unregister(&self, wr_id: WorkRequestId) {
+        let _ = self.req_map.remove_with_guard(&wr_id, &pin());
+    }
+
+    pub fn stop(&self) {
+        self.poller_handle.abort();
+    }
+}
+
+impl Drop for EventListener {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Wakes the requests of a queue pair that can no longer complete
+#[derive(Default)]
+pub struct Failure {
+    state: Mutex<FailureState>,
+    wakers: Mutex<HashMap<WorkRequestId, Waker>>,
+}
+
+#[derive(Default)]
+struct FailureState {
+    reason: Option<String>,
+    /// The QP is in the error state, the device flushes its WRs instead of dropping them
+    flushed: bool,
+    /// Bumped by every reset, WRs posted before it never complete
+    generation: u64,
+}
+
+impl Failure {
+    pub fn fail(&self, reason: String) {
+        let _ = self.state.lock().unwrap().reason.get_or_insert(reason);
+        self.wake_all();
+    }
+
+    /// Like `fail`, but posted WRs keep waiting for their flush completions since the device
+    /// may use their buffers until then
+    pub fn fail_flushed(&self, reason: String) {
+        let mut state = self.state.lock().unwrap();
+        let _ = state.reason.get_or_insert(reason);
+        state.flushed = true;
+    }
+
+    /// Clears the failure, the WRs posted so far fail since a reset discards them
+    pub fn reset(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.reason = None;
+            state.flushed = false;
+            state.generation = state.generation.wrapping_add(1);
+        }
+        self.wake_all();
+    }
+
+    fn wake_all(&self) {
+        for (_, waker) in self.wakers.lock().unwrap().drain() {
+            waker.wake();
+        }
+    }
+
+    /// The generation WRs posted now belong to, see `poll`
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// Fails if the QP failed, otherwise returns the generation of WRs posted now
+    pub fn check(&self) -> io::Result<u64> {
+        let state = self.state.lock().unwrap();
+        match &state.reason {
+            Some(reason) => Err(failed_error(reason)),
+            None => Ok(state.generation),
+        }
+    }
+
+    /// Ready once the QP failed without flushing its WRs or a reset discarded the WRs of
+    /// `generation`
+    pub fn poll(
+        &self,
+        wr_id: WorkRequestId,
+        generation: u64,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Error> {
+        // Hold the state lock while registering so `fail` can not slip in between
+        let state = self.state.lock().unwrap();
+        if let Some(reason) = state.reason.as_ref().filter(|_| !state.flushed) {
+            return Poll::Ready(failed_error(reason));
+        }
+        if state.generation != generation {
+            return Poll::Ready(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "queue pair was reset before the work request completed",
+            ));
+        }
+        self.wakers
+            .lock()
+            .unwrap()
+            .insert(wr_id, cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Ready once a reset discarded the WRs of `generation`, a failure alone does not count
+    pub fn poll_reset(
+        &self,
+        wr_id: WorkRequestId,
+        generation: u64,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        let state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return Poll::Ready(());
+        }
+        self.wakers
+            .lock()
+            .unwrap()
+            .insert(wr_id, cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn unwatch(&self, wr_id: WorkRequestId) {
+        self.wakers.lock().unwrap().remove(&wr_id);
+    }
+}
+
+fn failed_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, reason.to_string())
 }
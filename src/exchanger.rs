@@ -0,0 +1,53 @@
+use crate::handshake::HandshakeMessage;
+use futures::future::BoxFuture;
+use std::io;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+pub trait Exchanger: Send {
+    fn exchange(&mut self, local: HandshakeMessage) -> BoxFuture<'_, io::Result<HandshakeMessage>>;
+}
+
+pub trait ExchangerListener: Send + Sync {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Exchanger>>>;
+}
+
+async fn stream_exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    local: HandshakeMessage,
+) -> io::Result<HandshakeMessage> {
+    stream.write_all(&local.to_bytes()).await?;
+    HandshakeMessage::read_from(stream).await
+}
+
+impl Exchanger for TcpStream {
+    fn exchange(&mut self, local: HandshakeMessage) -> BoxFuture<'_, io::Result<HandshakeMessage>> {
+        Box::pin(stream_exchange(self, local))
+    }
+}
+
+impl Exchanger for UnixStream {
+    fn exchange(&mut self, local: HandshakeMessage) -> BoxFuture<'_, io::Result<HandshakeMessage>> {
+        Box::pin(stream_exchange(self, local))
+    }
+}
+
+impl ExchangerListener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Exchanger>>> {
+        Box::pin(async move {
+            let (stream, _) = TcpListener::accept(self).await?;
+            Ok(Box::new(stream) as Box<dyn Exchanger>)
+        })
+    }
+}
+
+impl ExchangerListener for UnixListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Box<dyn Exchanger>>> {
+        Box::pin(async move {
+            let (stream, _) = UnixListener::accept(self).await?;
+            Ok(Box::new(stream) as Box<dyn Exchanger>)
+        })
+    }
+}
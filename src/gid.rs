@@ -1,4 +1,5 @@
 use rdma_sys::ibv_gid;
+use std::net::IpAddr;
 
 #[derive(
     serde::Serialize, serde::Deserialize, Default, Copy, Clone, Debug, Eq, PartialEq, Hash,
@@ -8,6 +9,12 @@ pub struct Gid {
     raw: [u8; 16],
 }
 
+impl Gid {
+    pub fn as_raw(&self) -> &[u8; 16] {
+        &self.raw
+    }
+}
+
 #[allow(dead_code)]
 impl Gid {
     fn subnet_prefix(&self) -> u64 {
@@ -19,6 +26,22 @@ impl Gid {
     }
 }
 
+impl From<IpAddr> for Gid {
+    fn from(ip: IpAddr) -> Self {
+        let raw = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        Self { raw }
+    }
+}
+
+impl From<[u8; 16]> for Gid {
+    fn from(raw: [u8; 16]) -> Self {
+        Self { raw }
+    }
+}
+
 impl From<ibv_gid> for Gid {
     fn from(gid: ibv_gid) -> Self {
         Self {
@@ -44,3 +67,16 @@ impl AsMut<rdma_sys::ibv_gid> for Gid {
         unsafe { &mut *self.raw.as_mut_ptr().cast::<ibv_gid>() }
     }
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GidSelector {
+    Index(u8),
+    /// The RoCE v2 GID of this address on the selected port
+    RoceV2(IpAddr),
+}
+
+impl Default for GidSelector {
+    fn default() -> Self {
+        Self::Index(1)
+    }
+}
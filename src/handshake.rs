@@ -0,0 +1,257 @@
+use crate::queue_pair::{QueuePair, QueuePairEndpoint, QueuePairType};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const HANDSHAKE_MAGIC: u32 = 0x5244_4d41;
+const HANDSHAKE_VERSION: u16 = 3;
+const HANDSHAKE_HEADER_SIZE: usize = 10;
+const HANDSHAKE_MAX_BODY_SIZE: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct HandshakeMessage {
+    endpoint: QueuePairEndpoint,
+    qp_type: QueuePairType,
+    start_psn: u32,
+    mtu: u32,
+    max_send_wr: u32,
+    max_recv_wr: u32,
+    max_send_sge: u32,
+    max_recv_sge: u32,
+    max_rd_atomic: u8,
+    max_dest_rd_atomic: u8,
+    message_size: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Negotiated {
+    pub remote: QueuePairEndpoint,
+    pub path_mtu: u32,
+    pub local_psn: u32,
+    pub remote_psn: u32,
+    /// RDMA reads and atomics this side issues at once, the peer serves that many
+    pub rd_atomic: u8,
+    /// RDMA reads and atomics of the peer this side serves at once
+    pub dest_rd_atomic: u8,
+}
+
+impl HandshakeMessage {
+    pub(crate) fn new(qp: &QueuePair, message_size: usize) -> Self {
+        let cap = qp.cap();
+        Self {
+            endpoint: qp.endpoint(),
+            qp_type: qp.qp_type(),
+            start_psn: rand::thread_rng().gen::<u32>() & 0xff_ffff,
+            mtu: qp.active_mtu(),
+            max_send_wr: cap.max_send_wr,
+            max_recv_wr: cap.max_recv_wr,
+            max_send_sge: cap.max_send_sge,
+            max_recv_sge: cap.max_recv_sge,
+            max_rd_atomic: qp.config().max_rd_atomic,
+            max_dest_rd_atomic: qp.max_dest_rd_atomic(),
+            message_size: message_size as u64,
+        }
+    }
+
+    pub(crate) fn negotiate(&self, remote: &HandshakeMessage) -> io::Result<Negotiated> {
+        if self.message_size != remote.message_size {
+            return Err(incompatible(format!(
+                "agent message size mismatch, local {} remote {}",
+                self.message_size, remote.message_size
+            )));
+        }
+        if self.qp_type != remote.qp_type {
+            return Err(incompatible(format!(
+                "queue pair type mismatch, local {:?} remote {:?}",
+                self.qp_type, remote.qp_type
+            )));
+        }
+        if remote.max_recv_wr == 0 || remote.max_recv_sge == 0 {
+            return Err(incompatible(format!(
+                "remote receive queue is too small, max_recv_wr {} max_recv_sge {}",
+                remote.max_recv_wr, remote.max_recv_sge
+            )));
+        }
+        // Each side issues as many reads as it asked for and the other side can serve
+        let rd_atomic = self.max_rd_atomic.min(remote.max_dest_rd_atomic);
+        let dest_rd_atomic = remote.max_rd_atomic.min(self.max_dest_rd_atomic);
+        let reads = self.qp_type == QueuePairType::Rc;
+        if reads && (rd_atomic == 0 || dest_rd_atomic == 0) {
+            return Err(incompatible(format!(
+                "both sides must allow at least one outstanding RDMA read, local issues {} \
+                 serves {} remote issues {} serves {}",
+                self.max_rd_atomic,
+                self.max_dest_rd_atomic,
+                remote.max_rd_atomic,
+                remote.max_dest_rd_atomic
+            )));
+        }
+        Ok(Negotiated {
+            remote: remote.endpoint,
+            path_mtu: self.mtu.min(remote.mtu),
+            local_psn: self.start_psn,
+            remote_psn: remote.start_psn,
+            rd_atomic,
+            dest_rd_atomic,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body = bincode::serialize(self).unwrap();
+        let mut frame = Vec::with_capacity(HANDSHAKE_HEADER_SIZE + body.len());
+        frame.extend_from_slice(&HANDSHAKE_MAGIC.to_be_bytes());
+        frame.extend_from_slice(&HANDSHAKE_VERSION.to_be_bytes());
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < HANDSHAKE_HEADER_SIZE {
+            return Err(incompatible(format!(
+                "handshake message of {} bytes is shorter than its header",
+                bytes.len()
+            )));
+        }
+        let (header, body) = bytes.split_at(HANDSHAKE_HEADER_SIZE);
+        let len = Self::parse_header(header.try_into().unwrap())?;
+        if body.len() != len {
+            return Err(incompatible(format!(
+                "handshake body has {} bytes, header announces {}",
+                body.len(),
+                len
+            )));
+        }
+        Self::parse_body(body)
+    }
+
+    pub async fn read_from<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Self> {
+        let mut header = [0_u8; HANDSHAKE_HEADER_SIZE];
+        stream.read_exact(&mut header).await?;
+        let mut body = vec![0_u8; Self::parse_header(&header)?];
+        stream.read_exact(&mut body).await?;
+        Self::parse_body(&body)
+    }
+
+    fn parse_header(header: &[u8; HANDSHAKE_HEADER_SIZE]) -> io::Result<usize> {
+        let magic = u32::from_be_bytes(header[0..4].try_into().unwrap());
+        if magic != HANDSHAKE_MAGIC {
+            return Err(incompatible(format!(
+                "bad handshake magic {:#x}, peer is not an async-rdma endpoint",
+                magic
+            )));
+        }
+        let version = u16::from_be_bytes(header[4..6].try_into().unwrap());
+        if version != HANDSHAKE_VERSION {
+            return Err(incompatible(format!(
+                "unsupported handshake version {}, local version {}",
+                version, HANDSHAKE_VERSION
+            )));
+        }
+        let len = u32::from_be_bytes(header[6..10].try_into().unwrap()) as usize;
+        if len > HANDSHAKE_MAX_BODY_SIZE {
+            return Err(incompatible(format!(
+                "handshake message of {} bytes exceeds limit {}",
+                len, HANDSHAKE_MAX_BODY_SIZE
+            )));
+        }
+        Ok(len)
+    }
+
+    fn parse_body(body: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(body)
+            .map_err(|e| incompatible(format!("malformed handshake message: {}", e)))
+    }
+}
+
+fn incompatible(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HandshakeMessage;
+    use crate::*;
+
+    fn message() -> io::Result<HandshakeMessage> {
+        RdmaBuilder::default().build()?.handshake_message()
+    }
+
+    #[test]
+    fn frame() -> io::Result<()> {
+        let local = message()?;
+        assert!(local.start_psn <= 0xff_ffff);
+        let bytes = local.to_bytes();
+        let parsed = HandshakeMessage::from_bytes(&bytes)?;
+        assert_eq!(parsed.start_psn, local.start_psn);
+        assert_eq!(parsed.endpoint, local.endpoint);
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 0xff;
+        let mut bad_version = bytes.clone();
+        bad_version[4..6].copy_from_slice(&1_u16.to_be_bytes());
+        let mut oversize = bytes.clone();
+        oversize[6..10].copy_from_slice(&4096_u32.to_be_bytes());
+        for (bytes, msg) in [
+            (bad_magic, "magic"),
+            (bad_version, "version"),
+            (oversize, "exceeds limit"),
+            (bytes[..bytes.len() - 1].to_vec(), "header announces"),
+        ] {
+            let err = HandshakeMessage::from_bytes(&bytes).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(msg), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversize_body() -> io::Result<()> {
+        let mut bytes = message()?.to_bytes();
+        bytes[6..10].copy_from_slice(&u32::MAX.to_be_bytes());
+        // Rejected from the header, the body is never read
+        let err = HandshakeMessage::read_from(&mut &bytes[..])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate() -> io::Result<()> {
+        let mut local = message()?;
+        let mut remote = message()?;
+        local.max_rd_atomic = 4;
+        local.max_dest_rd_atomic = 8;
+        remote.max_rd_atomic = 16;
+        remote.max_dest_rd_atomic = 2;
+        remote.mtu = rdma_sys::ibv_mtu::IBV_MTU_256;
+        let negotiated = local.negotiate(&remote)?;
+        assert_eq!(negotiated.rd_atomic, 2);
+        assert_eq!(negotiated.dest_rd_atomic, 8);
+        assert_eq!(negotiated.path_mtu, remote.mtu);
+        assert_eq!(negotiated.local_psn, local.start_psn);
+        assert_eq!(negotiated.remote_psn, remote.start_psn);
+
+        let mut mismatches = vec![];
+        let mut other = remote;
+        other.message_size += 1;
+        mismatches.push((other, "message size"));
+        let mut other = remote;
+        other.qp_type = QueuePairType::Uc;
+        mismatches.push((other, "type mismatch"));
+        let mut other = remote;
+        other.max_recv_wr = 0;
+        mismatches.push((other, "receive queue"));
+        let mut other = remote;
+        other.max_dest_rd_atomic = 0;
+        mismatches.push((other, "RDMA read"));
+        for (remote, msg) in mismatches {
+            let err = local.negotiate(&remote).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(msg), "{}", err);
+        }
+        Ok(())
+    }
+}
@@ -1,63 +1,351 @@
+mod address_handle;
 mod agent;
+mod async_event;
 mod completion_queue;
+mod connect_policy;
+mod connection_manager;
 mod context;
+mod datagram;
+mod device;
 mod event_channel;
 mod event_listener;
+mod exchanger;
 mod gid;
+mod handshake;
 mod memory_region;
 mod memory_window;
 mod mr_allocator;
 mod protection_domain;
 mod queue_pair;
+mod shared_receive_queue;
+mod work_queue;
 mod work_request;
 
-use agent::Agent;
-use context::Context;
+use agent::{closed_error, Agent, MESSAGE_MAX_SIZE};
+use connect_policy::ConnectPolicy;
+use connection_manager::{CmEventChannel, CmId, CmListener};
+use context::{Context, DEFAULT_PORT_NUM};
+use datagram::DEFAULT_QKEY;
 use event_listener::EventListener;
+use futures::{stream::BoxStream, Stream};
 use memory_region::{LocalMemoryRegion, RemoteMemoryRegion};
 use mr_allocator::MRAllocator;
 use protection_domain::ProtectionDomain;
 use queue_pair::{QueuePair, QueuePairEndpoint};
-use rdma_sys::ibv_access_flags;
-use std::{alloc::Layout, any::Any, fmt::Debug, io, sync::Arc};
+use rdma_sys::{ibv_access_flags, ibv_qp_state};
+use std::{
+    alloc::Layout,
+    any::Any,
+    fmt::Debug,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::Poll,
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Notify},
+    task::JoinHandle,
 };
 use tracing::debug;
 
+pub use address_handle::{AddressHandle, DatagramEndpoint, DatagramSource, Grh, GRH_LEN};
+pub use async_event::AsyncEvent;
+pub use completion_queue::{WCError, WcOpcode, WorkCompletion, WorkRequestId};
+pub use connect_policy::{ConnectStep, ConnectTimeout};
+pub use datagram::Datagram;
+pub use device::{
+    devices, AtomicCap, DeviceAttr, DeviceInfo, GidEntry, GidType, LinkLayer, PortInfo, PortState,
+};
+pub use exchanger::{Exchanger, ExchangerListener};
+pub use gid::{Gid, GidSelector};
+pub use handshake::HandshakeMessage;
+pub use queue_pair::{
+    state, BufResult, OperationTimeout, QueuePairConfig, QueuePairState, QueuePairType, Received,
+    Reclaim, SendBatch, TypedQueuePair,
+};
+pub use shared_receive_queue::{SharedReceiveQueue, SrqConfig};
+
 #[macro_use]
 extern crate lazy_static;
 
+const CM_LISTEN_BACKLOG: i32 = 128;
+const INCOMING_CHANNEL_SIZE: usize = 128;
+/// Wait after a failed accept in `incoming`, doubled while accepts keep failing
+const INCOMING_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const INCOMING_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
 pub struct RdmaBuilder {
     dev_name: Option<String>,
+    port_num: u8,
+    gid: GidSelector,
     access: ibv_access_flags,
     cq_size: u32,
+    qp_type: QueuePairType,
+    qp_config: QueuePairConfig,
+    srq: Option<SrqConfig>,
+    qkey: u32,
+    policy: ConnectPolicy,
+    request_timeout: Option<Duration>,
 }
 
 impl RdmaBuilder {
     pub fn build(&self) -> io::Result<Rdma> {
-        Rdma::new(self.dev_name.as_deref(), self.access, self.cq_size)
+        let mut rdma = SharedResources::open(self, self.dev_name.as_deref())?.new_rdma()?;
+        rdma.init_qp()?;
+        Ok(rdma)
+    }
+
+    /// A UD endpoint instead of a connection, the SRQ setting does not apply to it
+    pub fn build_datagram(&self) -> io::Result<Datagram> {
+        let shared = SharedResources::open(self, self.dev_name.as_deref())?;
+        Datagram::new(
+            &shared.ctx,
+            &shared.pd,
+            shared.allocator,
+            self.cq_size,
+            self.qp_config,
+            self.qkey,
+        )
+    }
+
+    pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<Rdma> {
+        self.policy
+            .with_deadline(async {
+                let addr = resolve_addr(addr).await?;
+                let stream = self
+                    .policy
+                    .retry(ConnectStep::Connect, || {
+                        self.policy
+                            .step(ConnectStep::Connect, TcpStream::connect(addr))
+                    })
+                    .await?;
+                self.connect_exchange(stream).await
+            })
+            .await
+    }
+
+    pub async fn connect_with<E: Exchanger>(&self, exchanger: E) -> io::Result<Rdma> {
+        self.policy
+            .with_deadline(self.connect_exchange(exchanger))
+            .await
+    }
+
+    async fn connect_exchange<E: Exchanger>(&self, mut exchanger: E) -> io::Result<Rdma> {
+        let mut rdma = self.build()?;
+        self.policy
+            .step(
+                ConnectStep::ExchangeEndpoint,
+                rdma.exchange_handshake(&mut exchanger),
+            )
+            .await?;
+        rdma.init_agent();
+        Ok(rdma)
+    }
+
+    pub async fn cm_connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<Rdma> {
+        self.expect_cm_qp_type()?;
+        // Opened by the first attempt that resolves a route, later ones reuse them
+        let opened = Mutex::new(None);
+        self.policy
+            .with_deadline(async {
+                let addr = resolve_addr(addr).await?;
+                self.policy
+                    .retry(ConnectStep::CmConnect, || {
+                        self.cm_connect_once(addr, &opened)
+                    })
+                    .await
+            })
+            .await
+    }
+
+    async fn cm_connect_once(
+        &self,
+        addr: SocketAddr,
+        opened: &Mutex<Option<SharedResources>>,
+    ) -> io::Result<Rdma> {
+        let cm_id = CmId::new(Arc::new(CmEventChannel::new()?))?;
+        let timeout_ms = self.policy.step_timeout.as_millis().min(i32::MAX as u128) as i32;
+        self.policy
+            .step(ConnectStep::ResolveRoute, cm_id.resolve(addr, timeout_ms))
+            .await?;
+        let verbs = cm_id.verbs()?;
+        let shared = {
+            let mut opened = opened.lock().unwrap();
+            match &*opened {
+                // The route may change to another device between attempts
+                Some(shared) if shared.ctx.as_ptr() == verbs.as_ptr() => shared.clone(),
+                _ => opened
+                    .insert(SharedResources::open_cm(self, &cm_id)?)
+                    .clone(),
+            }
+        };
+        let mut rdma = Rdma::cm_build(&shared, &cm_id)?;
+        self.policy
+            .step(
+                ConnectStep::CmConnect,
+                cm_id.connect(rdma.qp.qp_num(), &rdma.qp.config()),
+            )
+            .await?;
+        rdma.cm_ready(&cm_id)?;
+        cm_id.establish()?;
+        rdma.cm_id = Some(cm_id);
+        rdma.init_agent();
+        Ok(rdma)
+    }
+
+    /// The CM negotiates RC attributes, other QP types need an exchanger
+    fn expect_cm_qp_type(&self) -> io::Result<()> {
+        if self.qp_type != QueuePairType::Rc {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "cm connections do not support {:?} queue pairs",
+                    self.qp_type
+                ),
+            ));
+        }
+        Ok(())
     }
 
     pub fn set_dev(&mut self, dev: &str) {
         self.dev_name = Some(dev.to_string());
     }
 
+    pub fn set_port_num(&mut self, port_num: u8) {
+        self.port_num = port_num;
+    }
+
+    pub fn set_gid_index(&mut self, gid_index: u8) {
+        self.gid = GidSelector::Index(gid_index);
+    }
+
+    pub fn set_roce_v2_addr(&mut self, addr: IpAddr) {
+        self.gid = GidSelector::RoceV2(addr);
+    }
+
+    /// The initial CQ size, the CQ grows to hold the work queues of every QP completing on it
     pub fn set_cq_size(&mut self, cq_size: u32) {
         self.cq_size = cq_size
     }
+
+    /// UC connections only carry sends and writes, reads on them are refused. UC drops lost
+    /// messages silently, so agent requests need `set_request_timeout` to fail instead of hang
+    pub fn set_qp_type(&mut self, qp_type: QueuePairType) {
+        self.qp_type = qp_type;
+    }
+
+    pub fn set_qp_config(&mut self, config: QueuePairConfig) {
+        self.qp_config = config;
+    }
+
+    /// Connections from the same builder or listener receive through one shared SRQ
+    pub fn set_srq(&mut self, config: SrqConfig) {
+        self.srq = Some(config);
+    }
+
+    /// The QKey of datagram endpoints, peers address it with the same key
+    pub fn set_qkey(&mut self, qkey: u32) {
+        self.qkey = qkey;
+    }
+
+    pub fn set_max_send_wr(&mut self, max_send_wr: u32) {
+        self.qp_config.max_send_wr = max_send_wr;
+    }
+
+    pub fn set_max_recv_wr(&mut self, max_recv_wr: u32) {
+        self.qp_config.max_recv_wr = max_recv_wr;
+    }
+
+    pub fn set_max_send_sge(&mut self, max_send_sge: u32) {
+        self.qp_config.max_send_sge = max_send_sge;
+    }
+
+    pub fn set_max_recv_sge(&mut self, max_recv_sge: u32) {
+        self.qp_config.max_recv_sge = max_recv_sge;
+    }
+
+    /// The default of 64 bytes shrinks to what the device supports, a value set here has to fit
+    pub fn set_max_inline_data(&mut self, max_inline_data: u32) {
+        self.qp_config.max_inline_data = max_inline_data;
+    }
+
+    pub fn set_sq_sig_all(&mut self, sq_sig_all: bool) {
+        self.qp_config.sq_sig_all = sq_sig_all;
+    }
+
+    /// Signal only every `signal_interval`th send WR, see `QueuePairConfig::signal_interval`
+    pub fn set_signal_interval(&mut self, signal_interval: u32) {
+        self.qp_config.signal_interval = signal_interval;
+    }
+
+    pub fn set_qp_timeout(&mut self, timeout: u8) {
+        self.qp_config.timeout = timeout;
+    }
+
+    pub fn set_retry_cnt(&mut self, retry_cnt: u8) {
+        self.qp_config.retry_cnt = retry_cnt;
+    }
+
+    pub fn set_rnr_retry(&mut self, rnr_retry: u8) {
+        self.qp_config.rnr_retry = rnr_retry;
+    }
+
+    pub fn set_min_rnr_timer(&mut self, min_rnr_timer: u8) {
+        self.qp_config.min_rnr_timer = min_rnr_timer;
+    }
+
+    pub fn set_max_rd_atomic(&mut self, max_rd_atomic: u8) {
+        self.qp_config.max_rd_atomic = max_rd_atomic;
+    }
+
+    pub fn set_connect_deadline(&mut self, deadline: Duration) {
+        self.policy.deadline = Some(deadline);
+    }
+
+    pub fn set_connect_retry(
+        &mut self,
+        retries: usize,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) {
+        self.policy.retries = retries;
+        self.policy.initial_backoff = initial_backoff;
+        self.policy.max_backoff = max_backoff;
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.policy.step_timeout = timeout;
+    }
+
+    /// Agent requests such as remote MR allocation fail with `OperationTimeout` after `timeout`
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = Some(timeout);
+    }
 }
 
 impl Default for RdmaBuilder {
     fn default() -> Self {
         Self {
             dev_name: None,
+            port_num: DEFAULT_PORT_NUM,
+            gid: GidSelector::default(),
             access: ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
                 | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
                 | ibv_access_flags::IBV_ACCESS_REMOTE_READ
                 | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC,
             cq_size: 16,
+            qp_type: QueuePairType::Rc,
+            qp_config: QueuePairConfig::default(),
+            srq: None,
+            qkey: DEFAULT_QKEY,
+            policy: ConnectPolicy::default(),
+            request_timeout: None,
         }
     }
 }
@@ -68,79 +356,502 @@ pub struct Rdma {
     pd: Arc<ProtectionDomain>,
     allocator: Arc<MRAllocator>,
     qp: Arc<QueuePair>,
+    qps: Arc<RwLock<Vec<Arc<QueuePair>>>>,
+    next_qp: AtomicUsize,
     agent: Option<Arc<Agent>>,
+    access: ibv_access_flags,
+    cm_id: Option<CmId>,
+    closed: AtomicBool,
+    inflight: AtomicUsize,
+    drained: Notify,
+    pending_qp: Option<TypedQueuePair<state::Init>>,
+    /// Sent to the peer for `pending_qp`, its start PSN is the one the QP moves to RTS with
+    pending_handshake: Option<HandshakeMessage>,
+    request_timeout: Option<Duration>,
+}
+
+struct InflightGuard<'a> {
+    rdma: &'a Rdma,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        if self.rdma.inflight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.rdma.drained.notify_waiters();
+        }
+    }
 }
 
 impl Rdma {
     pub fn new(dev_name: Option<&str>, access: ibv_access_flags, cq_size: u32) -> io::Result<Self> {
-        let ctx = Arc::new(Context::open(dev_name)?);
-        let ec = ctx.create_event_channel()?;
-        let cq = Arc::new(ctx.create_completion_queue(cq_size, Some(ec))?);
-        let event_listener = EventListener::new(cq);
-        let pd = Arc::new(ctx.create_protection_domain()?);
-        let allocator = Arc::new(MRAllocator::new(pd.clone()));
-        let qp = Arc::new(
-            pd.create_queue_pair_builder()
-                .set_event_listener(event_listener)
-                .build()?,
-        );
-        qp.modify_to_init(access)?;
-        Ok(Self {
-            ctx,
-            pd,
-            qp,
-            agent: None,
-            allocator,
+        RdmaBuilder {
+            dev_name: dev_name.map(|dev| dev.to_string()),
+            access,
+            cq_size,
+            ..Default::default()
+        }
+        .build()
+    }
+
+    fn init_agent(&mut self) {
+        let agent = Arc::new(Agent::new(
+            self.qp.clone(),
+            self.qps.clone(),
+            self.allocator.clone(),
+            self.access,
+            self.request_timeout,
+        ));
+        self.agent = Some(agent);
+    }
+
+    /// Resets QP 0 and moves it to INIT, ready for a handshake
+    fn init_qp(&mut self) -> io::Result<()> {
+        let qp = TypedQueuePair::reset(self.qp.clone())?.into_init(self.access)?;
+        self.pending_handshake = Some(HandshakeMessage::new(qp.qp(), MESSAGE_MAX_SIZE));
+        self.pending_qp = Some(qp);
+        Ok(())
+    }
+
+    fn take_pending_qp(&mut self) -> io::Result<TypedQueuePair<state::Init>> {
+        self.pending_qp.take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "queue pair is not in INIT state",
+            )
         })
     }
 
-    pub fn endpoint(&self) -> QueuePairEndpoint {
-        self.qp.endpoint()
+    fn cm_build(shared: &SharedResources, cm_id: &CmId) -> io::Result<Self> {
+        let mut rdma = shared.new_rdma()?;
+        let (mut attr, mask) = cm_id.init_qp_attr(ibv_qp_state::IBV_QPS_INIT)?;
+        attr.qp_access_flags = rdma.access.0;
+        let qp = TypedQueuePair::reset(rdma.qp.clone())?.into_init_with(&mut attr, mask)?;
+        rdma.pending_qp = Some(qp);
+        Ok(rdma)
     }
 
-    pub fn handshake(&mut self, remote: QueuePairEndpoint) -> io::Result<()> {
-        self.qp.modify_to_rtr(remote, 0, 1, 0x12)?;
+    fn cm_ready(&mut self, cm_id: &CmId) -> io::Result<()> {
+        let qp = self.take_pending_qp()?;
+        let (mut attr, mask) = cm_id.init_qp_attr(ibv_qp_state::IBV_QPS_RTR)?;
+        let qp = qp.into_rtr_with(&mut attr, mask)?;
         debug!("rtr");
-        self.qp.modify_to_rts(0x12, 6, 7, 0, 1)?;
+        let (mut attr, mask) = cm_id.init_qp_attr(ibv_qp_state::IBV_QPS_RTS)?;
+        let _qp = qp.into_rts_with(&mut attr, mask)?;
         debug!("rts");
         Ok(())
     }
 
+    pub fn endpoint(&self) -> QueuePairEndpoint {
+        self.qp.endpoint()
+    }
+
+    /// Handed to the peer's `handshake`, it carries a random start PSN and the QP caps
+    pub fn handshake_message(&self) -> io::Result<HandshakeMessage> {
+        self.pending_handshake.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "queue pair is not in INIT state",
+            )
+        })
+    }
+
+    /// Connects to the peer that sent `remote` out of band, see `handshake_message`
+    pub fn handshake(&mut self, remote: HandshakeMessage) -> io::Result<()> {
+        let negotiated = self.handshake_message()?.negotiate(&remote)?;
+        self.take_pending_qp()?.handshake(&negotiated)?;
+        self.pending_handshake = None;
+        Ok(())
+    }
+
+    async fn exchange_handshake<E: Exchanger + ?Sized>(
+        &mut self,
+        exchanger: &mut E,
+    ) -> io::Result<()> {
+        let remote = exchanger.exchange(self.handshake_message()?).await?;
+        self.handshake(remote)
+    }
+
+    pub fn query_state(&self) -> io::Result<QueuePairState> {
+        self.qp.query_state()
+    }
+
+    pub fn query_qp_state(&self, index: usize) -> io::Result<QueuePairState> {
+        self.qp_at(index)?.query_state()
+    }
+
+    /// Reconnects QP 0 after it failed, the peer has to recover with a matching exchanger.
+    /// The agent is restarted, memory regions handed out through the old one are released.
+    pub async fn recover_with<E: Exchanger>(&mut self, mut exchanger: E) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        if let Some(agent) = self.agent.take() {
+            agent.stop().await;
+        }
+        self.init_qp()?;
+        self.exchange_handshake(&mut exchanger).await?;
+        self.qp.watch_async_events();
+        self.init_agent();
+        Ok(())
+    }
+
+    /// Reconnects an extra QP after it failed, coordinated with the peer over QP 0
+    pub async fn recover_qp(&self, index: usize) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        if index == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "qp 0 carries the agent, recover it with recover_with",
+            ));
+        }
+        let qp = self.qp_at(index)?;
+        match &self.agent {
+            Some(agent) => agent.recover_qp(qp).await,
+            None => Err(not_connected_error()),
+        }
+    }
+
+    fn begin_op(&self) -> io::Result<InflightGuard<'_>> {
+        self.inflight.fetch_add(1, Ordering::AcqRel);
+        let guard = InflightGuard { rdma: self };
+        if self.closed.load(Ordering::Acquire) {
+            return Err(closed_error());
+        }
+        Ok(guard)
+    }
+
+    pub async fn close(&self) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        loop {
+            let drained = self.drained.notified();
+            if self.inflight.load(Ordering::Acquire) == 0 {
+                break;
+            }
+            drained.await;
+        }
+        debug!("in-flight operations drained");
+        let ans = match &self.agent {
+            Some(agent) => agent.close().await,
+            None => Ok(()),
+        };
+        if let Some(cm_id) = &self.cm_id {
+            cm_id.disconnect();
+        }
+        // Every QP is shut down even if another one fails to, the first error is reported
+        let shutdown = self
+            .qps
+            .read()
+            .unwrap()
+            .iter()
+            .skip(1)
+            .chain([&self.qp])
+            .map(|qp| qp.shutdown())
+            .fold(Ok(()), |ans, shutdown| ans.and(shutdown));
+        ans.and(shutdown)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    pub fn is_remote_closed(&self) -> bool {
+        self.agent
+            .as_ref()
+            .map_or(false, |agent| agent.is_remote_closed())
+    }
+
+    pub async fn remote_closed(&self) {
+        if let Some(agent) = &self.agent {
+            agent.remote_closed().await
+        }
+    }
+
+    pub fn device_info(&self) -> io::Result<DeviceInfo> {
+        self.ctx.device_info()
+    }
+
+    pub fn async_events(&self) -> BoxStream<'static, AsyncEvent> {
+        self.ctx.async_events()
+    }
+
+    pub fn srq(&self) -> Option<Arc<SharedReceiveQueue>> {
+        self.qp.srq().cloned()
+    }
+
     pub async fn send(&self, lm: &LocalMemoryRegion) -> io::Result<()> {
+        let _guard = self.begin_op()?;
         self.agent.as_ref().unwrap().clone().send(lm).await
     }
 
-    pub async fn receive(&self) -> LocalMemoryRegion {
+    /// Sends a small `data` inline, the peer gets it from `receive` like any other send
+    pub async fn send_inline(&self, data: &[u8]) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        self.agent.as_ref().unwrap().clone().send_inline(data).await
+    }
+
+    /// Writes a small `data` inline, no local memory region needed
+    pub async fn write_inline(&self, data: &[u8], remote: &RemoteMemoryRegion) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        self.data_qp().write_inline(data, remote).await
+    }
+
+    /// Up to this many bytes are posted inline, see `RdmaBuilder::set_max_inline_data`
+    pub fn inline_threshold(&self) -> usize {
+        self.qp.inline_threshold()
+    }
+
+    pub async fn receive(&self) -> io::Result<LocalMemoryRegion> {
+        if self.is_closed() {
+            return Err(closed_error());
+        }
         self.agent.as_ref().unwrap().clone().receive().await
     }
 
+    /// Sends `lm` with `imm` alongside, large buffers are split and every part carries `imm`
+    pub async fn send_with_imm(&self, lm: &LocalMemoryRegion, imm: u32) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        self.agent
+            .as_ref()
+            .unwrap()
+            .clone()
+            .send_with_imm(lm, Some(imm))
+            .await
+    }
+
+    /// Like `receive`, also returns the immediate if the peer sent one
+    pub async fn receive_with_imm(&self) -> io::Result<(LocalMemoryRegion, Option<u32>)> {
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        self.agent
+            .as_ref()
+            .unwrap()
+            .clone()
+            .receive_with_imm()
+            .await
+    }
+
+    /// Not cancellation safe: a dropped future leaves the read running into `lm` while the
+    /// caller has it back, use `read_owned` under `select!`
     pub async fn read(
         &self,
         lm: &mut LocalMemoryRegion,
         rm: &RemoteMemoryRegion,
     ) -> io::Result<()> {
-        self.qp.read(lm, rm).await.map_err(|e| e.into())
+        let _guard = self.begin_op()?;
+        self.data_qp().read(lm, rm).await
     }
 
+    /// Not cancellation safe: a dropped future leaves the device reading `local` while the
+    /// caller may change it, use `write_owned` under `select!`
     pub async fn write(
         &self,
         local: &LocalMemoryRegion,
         remote: &RemoteMemoryRegion,
     ) -> io::Result<()> {
-        self.qp.write(local, remote).await.map_err(|e| e.into())
+        let _guard = self.begin_op()?;
+        self.data_qp().write(local, remote).await
+    }
+
+    /// Takes `lm` and hands it back with the result. If the future is dropped while the read
+    /// is in flight the buffer goes with the WR, nobody can touch it while the device does
+    pub async fn read_owned(
+        &self,
+        lm: LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+    ) -> BufResult<(), Reclaim> {
+        let _guard = match self.begin_op() {
+            Ok(guard) => guard,
+            Err(err) => return (Err(err), Reclaim::ready(lm)),
+        };
+        self.data_qp().read_owned(lm, rm).await
+    }
+
+    /// See `read_owned`
+    pub async fn write_owned(
+        &self,
+        lm: LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+    ) -> BufResult<(), Reclaim> {
+        let _guard = match self.begin_op() {
+            Ok(guard) => guard,
+            Err(err) => return (Err(err), Reclaim::ready(lm)),
+        };
+        self.data_qp().write_owned(lm, rm).await
+    }
+
+    /// Like `read_owned`, fails with `OperationTimeout` after `timeout`. A read still in
+    /// flight then keeps `lm` until it completes, the `Reclaim` resolves after that
+    pub async fn read_timeout(
+        &self,
+        lm: LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+        timeout: Duration,
+    ) -> BufResult<(), Reclaim> {
+        let _guard = match self.begin_op() {
+            Ok(guard) => guard,
+            Err(err) => return (Err(err), Reclaim::ready(lm)),
+        };
+        self.data_qp().read_owned(lm, rm).timeout(timeout).await
+    }
+
+    /// See `read_timeout`
+    pub async fn write_timeout(
+        &self,
+        lm: LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+        timeout: Duration,
+    ) -> BufResult<(), Reclaim> {
+        let _guard = match self.begin_op() {
+            Ok(guard) => guard,
+            Err(err) => return (Err(err), Reclaim::ready(lm)),
+        };
+        self.data_qp().write_owned(lm, rm).timeout(timeout).await
+    }
+
+    /// `remote` must be 8 bytes and 8 byte aligned, returns the value it held before
+    pub async fn compare_and_swap(
+        &self,
+        remote: &RemoteMemoryRegion,
+        expected: u64,
+        new: u64,
+    ) -> io::Result<u64> {
+        let _guard = self.begin_op()?;
+        let lm = self.allocator.alloc(Layout::new::<u64>())?;
+        self.data_qp()
+            .compare_and_swap(&lm, remote, expected, new)
+            .await
+    }
+
+    /// `remote` must be 8 bytes and 8 byte aligned, returns the value it held before
+    pub async fn fetch_and_add(&self, remote: &RemoteMemoryRegion, delta: u64) -> io::Result<u64> {
+        let _guard = self.begin_op()?;
+        let lm = self.allocator.alloc(Layout::new::<u64>())?;
+        self.data_qp().fetch_and_add(&lm, remote, delta).await
+    }
+
+    /// The operations of a batch are posted together on one QP, see `SendBatch::post`
+    pub fn batch<'a>(&'a self) -> io::Result<SendBatch<'a>> {
+        let guard = self.begin_op()?;
+        Ok(self.data_qp().batch().with_guard(guard))
+    }
+
+    /// Goes over the primary QP, the peer agent keeps a receive posted there to catch the immediate
+    pub async fn write_with_imm(
+        &self,
+        local: &LocalMemoryRegion,
+        remote: &RemoteMemoryRegion,
+        imm: u32,
+    ) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        self.qp.write_with_imm(local, remote, imm).await
+    }
+
+    /// Waits for a write with immediate from the peer, returns the immediate and the written length
+    pub async fn receive_write_imm(&self) -> io::Result<(u32, usize)> {
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        self.agent
+            .as_ref()
+            .unwrap()
+            .clone()
+            .receive_write_imm()
+            .await
+    }
+
+    pub async fn read_on(
+        &self,
+        qp: usize,
+        lm: &mut LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+    ) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        self.qp_at(qp)?.read(lm, rm).await
+    }
+
+    pub async fn write_on(
+        &self,
+        qp: usize,
+        local: &LocalMemoryRegion,
+        remote: &RemoteMemoryRegion,
+    ) -> io::Result<()> {
+        let _guard = self.begin_op()?;
+        self.qp_at(qp)?.write(local, remote).await
+    }
+
+    /// Opens another QP to the peer and returns its index, QP 0 is the one carrying agent traffic
+    pub async fn open_qp(&self) -> io::Result<usize> {
+        let _guard = self.begin_op()?;
+        match &self.agent {
+            Some(agent) => agent.open_qp().await,
+            None => Err(not_connected_error()),
+        }
+    }
+
+    /// WRs given up by a timeout or a dropped future that the device still owns, their buffers
+    /// stay allocated until the completions arrive
+    pub fn abandoned_in_flight(&self) -> usize {
+        self.qps
+            .read()
+            .unwrap()
+            .iter()
+            .map(|qp| qp.abandoned_in_flight())
+            .sum()
+    }
+
+    /// Send WRs posted on every QP so far, including the empty writes that complete unsignaled
+    /// WRs nothing else followed
+    pub fn send_wrs_posted(&self) -> u64 {
+        self.qps
+            .read()
+            .unwrap()
+            .iter()
+            .map(|qp| qp.send_wrs_posted())
+            .sum()
+    }
+
+    /// Completions polled from the CQ the QPs of this connection share
+    pub fn completions_polled(&self) -> u64 {
+        self.qp.completions_polled()
+    }
+
+    pub fn qp_count(&self) -> usize {
+        self.qps.read().unwrap().len()
+    }
+
+    fn qp_at(&self, index: usize) -> io::Result<Arc<QueuePair>> {
+        let qps = self.qps.read().unwrap();
+        qps.get(index).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no qp {}, {} opened", index, qps.len()),
+            )
+        })
+    }
+
+    /// Spreads reads and writes over the extra QPs, leaving QP 0 to the agent when possible
+    fn data_qp(&self) -> Arc<QueuePair> {
+        let qps = self.qps.read().unwrap();
+        if qps.len() == 1 {
+            return qps[0].clone();
+        }
+        let next = self.next_qp.fetch_add(1, Ordering::Relaxed);
+        qps[1 + next % (qps.len() - 1)].clone()
     }
 
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
-        let mut rdma = RdmaBuilder::default().build()?;
-        let mut stream = TcpStream::connect(addr).await?;
-        let mut endpoint = bincode::serialize(&rdma.endpoint()).unwrap();
-        stream.write_all(&endpoint).await?;
-        stream.read_exact(endpoint.as_mut()).await?;
-        let remote: QueuePairEndpoint = bincode::deserialize(&endpoint).unwrap();
-        rdma.handshake(remote)?;
-        let agent = Arc::new(Agent::new(rdma.qp.clone(), rdma.allocator.clone()));
-        rdma.agent = Some(agent);
-        Ok(rdma)
+        RdmaBuilder::default().connect(addr).await
+    }
+
+    pub async fn connect_with<E: Exchanger>(exchanger: E) -> io::Result<Self> {
+        RdmaBuilder::default().connect_with(exchanger).await
+    }
+
+    pub async fn cm_connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        RdmaBuilder::default().cm_connect(addr).await
     }
 
     pub fn alloc_local_mr(&self, layout: Layout) -> io::Result<LocalMemoryRegion> {
@@ -148,26 +859,28 @@ impl Rdma {
     }
 
     pub async fn alloc_remote_mr(&self, layout: Layout) -> io::Result<RemoteMemoryRegion> {
-        if let Some(agent) = &self.agent {
-            agent.alloc_mr(layout).await
-        } else {
-            panic!();
+        let _guard = self.begin_op()?;
+        match &self.agent {
+            Some(agent) => agent.alloc_mr(layout).await,
+            None => Err(not_connected_error()),
         }
     }
 
     pub async fn send_mr(&self, mr: Arc<dyn Any + Send + Sync>) -> io::Result<()> {
-        if let Some(agent) = &self.agent {
-            agent.send_mr(mr).await
-        } else {
-            panic!();
+        let _guard = self.begin_op()?;
+        match &self.agent {
+            Some(agent) => agent.send_mr(mr).await,
+            None => Err(not_connected_error()),
         }
     }
 
     pub async fn receive_mr(&self) -> io::Result<Arc<dyn Any + Send + Sync>> {
-        if let Some(agent) = &self.agent {
-            agent.receive_mr().await
-        } else {
-            panic!();
+        if self.is_closed() {
+            return Err(closed_error());
+        }
+        match &self.agent {
+            Some(agent) => agent.receive_mr().await,
+            None => Err(not_connected_error()),
         }
     }
 
@@ -180,29 +893,353 @@ impl Rdma {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone)]
+struct SharedResources {
+    ctx: Arc<Context>,
+    pd: Arc<ProtectionDomain>,
+    allocator: Arc<MRAllocator>,
+    access: ibv_access_flags,
+    cq_size: u32,
+    qp_type: QueuePairType,
+    qp_config: QueuePairConfig,
+    srq: Option<Arc<SharedReceiveQueue>>,
+    policy: ConnectPolicy,
+    request_timeout: Option<Duration>,
+}
+
+impl SharedResources {
+    fn open(builder: &RdmaBuilder, dev_name: Option<&str>) -> io::Result<Self> {
+        let ctx = Context::open_port(dev_name, builder.port_num, builder.gid)?;
+        Self::with_context(builder, ctx)
+    }
+
+    /// On the context of `cm_id`, the QPs of its connections belong to the device the cm uses
+    fn open_cm(builder: &RdmaBuilder, cm_id: &CmId) -> io::Result<Self> {
+        let ctx = Context::from_verbs(cm_id.verbs()?, cm_id.port_num(), builder.gid)?;
+        Self::with_context(builder, ctx)
+    }
+
+    fn with_context(builder: &RdmaBuilder, ctx: Context) -> io::Result<Self> {
+        let ctx = Arc::new(ctx);
+        let pd = Arc::new(ctx.create_protection_domain()?);
+        let allocator = Arc::new(MRAllocator::new(pd.clone()));
+        let srq = match builder.srq {
+            Some(config) => {
+                // Completions of every connection on the SRQ end up in this CQ, it grows with
+                // each QP attached to it
+                let ec = ctx.create_event_channel()?;
+                let cq = Arc::new(ctx.create_completion_queue(builder.cq_size, Some(ec))?);
+                let srq = Arc::new(pd.create_srq(Arc::new(EventListener::new(cq)), config)?);
+                srq.start(allocator.clone(), Layout::new::<[u8; MESSAGE_MAX_SIZE]>())?;
+                Some(srq)
+            }
+            None => None,
+        };
+        Ok(Self {
+            ctx,
+            pd,
+            allocator,
+            access: builder.access,
+            cq_size: builder.cq_size,
+            qp_type: builder.qp_type,
+            qp_config: builder.qp_config,
+            srq,
+            policy: builder.policy,
+            request_timeout: builder.request_timeout,
+        })
+    }
+
+    fn new_rdma(&self) -> io::Result<Rdma> {
+        if self.qp_type == QueuePairType::Ud {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Ud queue pairs are not connected, use build_datagram",
+            ));
+        }
+        if self.qp_type == QueuePairType::Uc && self.request_timeout.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Uc queue pairs may lose agent messages, set a request timeout",
+            ));
+        }
+        let event_listener = match &self.srq {
+            Some(srq) => srq.event_listener().clone(),
+            None => {
+                let ec = self.ctx.create_event_channel()?;
+                let cq = Arc::new(self.ctx.create_completion_queue(self.cq_size, Some(ec))?);
+                Arc::new(EventListener::new(cq))
+            }
+        };
+        let mut builder = self
+            .pd
+            .create_queue_pair_builder()
+            .set_qp_type(self.qp_type)
+            .set_event_listener(event_listener)
+            .set_config(self.qp_config);
+        if let Some(srq) = &self.srq {
+            builder = builder.set_srq(srq.clone());
+        }
+        let qp = Arc::new(builder.build()?);
+        qp.watch_async_events();
+        Ok(Rdma {
+            ctx: self.ctx.clone(),
+            pd: self.pd.clone(),
+            pending_qp: None,
+            pending_handshake: None,
+            qps: Arc::new(RwLock::new(vec![qp.clone()])),
+            next_qp: AtomicUsize::new(0),
+            qp,
+            agent: None,
+            allocator: self.allocator.clone(),
+            access: self.access,
+            cm_id: None,
+            closed: AtomicBool::new(false),
+            inflight: AtomicUsize::new(0),
+            drained: Notify::new(),
+            request_timeout: self.request_timeout,
+        })
+    }
+}
+
+enum ListenerKind {
+    Exchange(Box<dyn ExchangerListener>),
+    Cm(CmListener),
+}
+
+impl Debug for ListenerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenerKind::Exchange(_) => f.write_str("Exchange"),
+            ListenerKind::Cm(cm_listener) => f.debug_tuple("Cm").field(cm_listener).finish(),
+        }
+    }
+}
+
+enum PendingConnection {
+    Exchange(Box<dyn Exchanger>),
+    Cm(CmId),
+}
+
 pub struct RdmaListener {
-    tcp_listener: TcpListener,
+    kind: ListenerKind,
+    shared: SharedResources,
+}
+
+impl Debug for RdmaListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RdmaListener")
+            .field("kind", &self.kind)
+            .field("dev_name", &self.shared.ctx.dev_name())
+            .finish()
+    }
 }
 
 impl RdmaListener {
     pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
-        let tcp_listener = TcpListener::bind(addr).await?;
-        Ok(Self { tcp_listener })
+        Self::bind_with_builder(addr, &RdmaBuilder::default()).await
+    }
+
+    pub async fn bind_with_builder<A: ToSocketAddrs>(
+        addr: A,
+        builder: &RdmaBuilder,
+    ) -> io::Result<Self> {
+        Self::with_exchanger(TcpListener::bind(addr).await?, builder)
+    }
+
+    pub fn with_exchanger<L: ExchangerListener + 'static>(
+        listener: L,
+        builder: &RdmaBuilder,
+    ) -> io::Result<Self> {
+        let shared = SharedResources::open(builder, builder.dev_name.as_deref())?;
+        Ok(Self {
+            kind: ListenerKind::Exchange(Box::new(listener)),
+            shared,
+        })
+    }
+
+    pub async fn cm_bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::cm_bind_with_builder(addr, &RdmaBuilder::default()).await
+    }
+
+    pub async fn cm_bind_with_builder<A: ToSocketAddrs>(
+        addr: A,
+        builder: &RdmaBuilder,
+    ) -> io::Result<Self> {
+        builder.expect_cm_qp_type()?;
+        let cm_listener = CmListener::bind(resolve_addr(addr).await?, CM_LISTEN_BACKLOG)?;
+        let shared = match (cm_listener.device_name(), &builder.dev_name) {
+            (Ok(bound), Some(dev_name)) if bound.ne(dev_name) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "address is bound to device {}, but device {} is required",
+                        bound, dev_name
+                    ),
+                ));
+            }
+            (Ok(_), _) => SharedResources::open_cm(builder, cm_listener.id())?,
+            // Requests to a wildcard address arrive on any device, only this one is accepted
+            (Err(_), dev_name) => SharedResources::open(builder, dev_name.as_deref())?,
+        };
+        Ok(Self {
+            kind: ListenerKind::Cm(cm_listener),
+            shared,
+        })
     }
 
     pub async fn accept(&self) -> io::Result<Rdma> {
-        let (mut stream, _) = self.tcp_listener.accept().await?;
-        let mut rdma = RdmaBuilder::default().build()?;
-        let mut remote = vec![0_u8; 22];
-        stream.read_exact(remote.as_mut()).await?;
-        let remote: QueuePairEndpoint = bincode::deserialize(&remote).unwrap();
-        let local = bincode::serialize(&rdma.endpoint()).unwrap();
-        stream.write_all(&local).await?;
-        rdma.handshake(remote)?;
+        let pending = self.accept_pending().await?;
+        Self::establish(&self.shared, pending).await
+    }
+
+    pub async fn accept_timeout(&self, timeout: Duration) -> io::Result<Rdma> {
+        let pending = tokio::time::timeout(timeout, self.accept_pending())
+            .await
+            .map_err(|_| ConnectTimeout::Step(ConnectStep::Accept, timeout))??;
+        Self::establish(&self.shared, pending).await
+    }
+
+    pub fn incoming(self) -> Incoming {
+        let (sender, receiver) = mpsc::channel(INCOMING_CHANNEL_SIZE);
+        let handle = tokio::spawn(async move {
+            let mut backoff = INCOMING_BACKOFF_MIN;
+            loop {
+                let sender = sender.clone();
+                match self.accept_pending().await {
+                    Ok(pending) => {
+                        backoff = INCOMING_BACKOFF_MIN;
+                        let shared = self.shared.clone();
+                        let _handshake = tokio::spawn(async move {
+                            let _ = sender.send(Self::establish(&shared, pending).await).await;
+                        });
+                    }
+                    Err(err) => {
+                        // A rejected request says nothing about the listener, other errors
+                        // such as running out of fds tend to repeat
+                        let refused = err.kind() == io::ErrorKind::ConnectionRefused;
+                        if sender.send(Err(err)).await.is_err() {
+                            break;
+                        }
+                        if !refused {
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(INCOMING_BACKOFF_MAX);
+                        }
+                    }
+                }
+            }
+        });
+        Incoming { receiver, handle }
+    }
+
+    async fn accept_pending(&self) -> io::Result<PendingConnection> {
+        match &self.kind {
+            ListenerKind::Exchange(listener) => {
+                Ok(PendingConnection::Exchange(listener.accept().await?))
+            }
+            ListenerKind::Cm(cm_listener) => {
+                let cm_id = cm_listener.get_request().await?;
+                let requested = cm_id.device_name().map_err(|err| {
+                    cm_id.reject();
+                    err
+                })?;
+                let dev_name = self.shared.ctx.dev_name();
+                if requested.ne(&dev_name) {
+                    cm_id.reject();
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!(
+                            "connection request arrived on device {}, but listener uses {}",
+                            requested, dev_name
+                        ),
+                    ));
+                }
+                Ok(PendingConnection::Cm(cm_id))
+            }
+        }
+    }
+
+    async fn establish(shared: &SharedResources, pending: PendingConnection) -> io::Result<Rdma> {
+        match pending {
+            PendingConnection::Exchange(exchanger) => {
+                Self::exchange_establish(shared, exchanger).await
+            }
+            PendingConnection::Cm(cm_id) => Self::cm_establish(shared, cm_id).await,
+        }
+    }
+
+    async fn exchange_establish(
+        shared: &SharedResources,
+        mut exchanger: Box<dyn Exchanger>,
+    ) -> io::Result<Rdma> {
+        let mut rdma = shared.new_rdma()?;
+        rdma.init_qp()?;
+        shared
+            .policy
+            .step(
+                ConnectStep::ExchangeEndpoint,
+                rdma.exchange_handshake(exchanger.as_mut()),
+            )
+            .await?;
         debug!("handshake done");
-        let agent = Arc::new(Agent::new(rdma.qp.clone(), rdma.allocator.clone()));
-        rdma.agent = Some(agent);
+        rdma.init_agent();
+        Ok(rdma)
+    }
+
+    async fn cm_establish(shared: &SharedResources, cm_id: CmId) -> io::Result<Rdma> {
+        let setup = Rdma::cm_build(shared, &cm_id).and_then(|mut rdma| {
+            rdma.cm_ready(&cm_id)?;
+            Ok(rdma)
+        });
+        let mut rdma = match setup {
+            Ok(rdma) => rdma,
+            Err(err) => {
+                cm_id.reject();
+                return Err(err);
+            }
+        };
+        shared
+            .policy
+            .step(
+                ConnectStep::CmAccept,
+                cm_id.accept(rdma.qp.qp_num(), &rdma.qp.config()),
+            )
+            .await?;
+        debug!("cm accept done");
+        rdma.cm_id = Some(cm_id);
+        rdma.init_agent();
         Ok(rdma)
     }
 }
+
+pub struct Incoming {
+    receiver: mpsc::Receiver<io::Result<Rdma>>,
+    handle: JoinHandle<()>,
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<Rdma>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn resolve_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address resolved"))
+}
+
+fn not_connected_error() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "rdma is not connected")
+}
@@ -54,6 +54,13 @@ impl<T: LocalRemoteMR> MemoryRegion<T> {
             inner: Arc::new(self.inner.alloc(layout)?),
         })
     }
+
+    /// Another handle to the same memory, it stays allocated and registered while any is alive
+    pub(crate) fn retain(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
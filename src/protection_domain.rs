@@ -1,4 +1,10 @@
-use crate::{context::Context, memory_region::LocalMemoryRegion, queue_pair::QueuePairBuilder};
+use crate::{
+    context::Context,
+    event_listener::EventListener,
+    memory_region::LocalMemoryRegion,
+    queue_pair::QueuePairBuilder,
+    shared_receive_queue::{SharedReceiveQueue, SrqConfig},
+};
 use rdma_sys::{ibv_access_flags, ibv_alloc_pd, ibv_dealloc_pd, ibv_pd};
 use std::{alloc::Layout, io, ptr::NonNull, sync::Arc};
 
@@ -25,6 +31,15 @@ impl ProtectionDomain {
         QueuePairBuilder::new(self)
     }
 
+    /// QPs attached to the SRQ have to deliver their completions to `event_listener`
+    pub fn create_srq(
+        self: &Arc<Self>,
+        event_listener: Arc<EventListener>,
+        config: SrqConfig,
+    ) -> io::Result<SharedReceiveQueue> {
+        SharedReceiveQueue::create(self, event_listener, config)
+    }
+
     pub fn alloc_memory_region(
         self: &Arc<Self>,
         layout: Layout,
@@ -1,26 +1,46 @@
 use crate::{
-    completion_queue::{WCError, WorkCompletion, WorkRequestId},
-    event_listener::EventListener,
+    address_handle::{AddressHandle, DatagramEndpoint, DatagramSource, Grh, GRH_LEN},
+    async_event::AsyncEvent,
+    completion_queue::{WorkCompletion, WorkRequestId},
+    device::{mtu_bytes, DeviceAttr},
+    event_listener::{EventListener, Failure},
     gid::Gid,
+    handshake::Negotiated,
     memory_region::{LocalMemoryRegion, RemoteMemoryRegion},
     protection_domain::ProtectionDomain,
+    shared_receive_queue::SharedReceiveQueue,
+    work_queue::{work_queue_slots, SlotAcquire},
     work_request::{RecvWr, SendWr},
 };
-use futures::{ready, Future};
+use futures::{future, ready, Future, StreamExt};
 use rdma_sys::{
     ibv_access_flags, ibv_cq, ibv_destroy_qp, ibv_modify_qp, ibv_post_recv, ibv_post_send, ibv_qp,
-    ibv_qp_attr, ibv_qp_attr_mask, ibv_qp_init_attr, ibv_qp_state, ibv_recv_wr, ibv_send_wr,
+    ibv_qp_attr, ibv_qp_attr_mask, ibv_qp_cap, ibv_qp_init_attr, ibv_qp_state, ibv_qp_type,
+    ibv_query_qp, ibv_recv_wr, ibv_send_wr,
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
     fmt::Debug,
     io,
+    marker::PhantomData,
+    mem,
     pin::Pin,
     ptr::{self, NonNull},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::Poll,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time::{Instant, Sleep},
 };
-use tokio::sync::mpsc;
 use tracing::debug;
 
 struct QueuePairInitAttr {
@@ -68,10 +88,127 @@ impl Debug for QueuePairInitAttr {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum QueuePairType {
+    /// Reliable connection
+    Rc,
+    /// Unreliable connection, writes and sends only, lost messages are not retransmitted
+    Uc,
+    /// Unreliable datagram
+    Ud,
+}
+
+impl QueuePairType {
+    fn as_raw(self) -> ibv_qp_type::Type {
+        match self {
+            Self::Rc => ibv_qp_type::IBV_QPT_RC,
+            Self::Uc => ibv_qp_type::IBV_QPT_UC,
+            Self::Ud => ibv_qp_type::IBV_QPT_UD,
+        }
+    }
+
+    fn unsupported(self, op: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is not supported on {:?} queue pairs", op, self),
+        )
+    }
+}
+
+/// Lowered to what the device supports, other values have to fit the device
+const DEFAULT_MAX_INLINE_DATA: u32 = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QueuePairConfig {
+    pub max_send_wr: u32,
+    pub max_recv_wr: u32,
+    pub max_send_sge: u32,
+    pub max_recv_sge: u32,
+    pub max_inline_data: u32,
+    pub sq_sig_all: bool,
+    pub timeout: u8,
+    pub retry_cnt: u8,
+    pub rnr_retry: u8,
+    pub min_rnr_timer: u8,
+    pub max_rd_atomic: u8,
+    /// Every this many send WRs one is signaled, the WRs in between complete along with it
+    pub signal_interval: u32,
+}
+
+impl Default for QueuePairConfig {
+    fn default() -> Self {
+        Self {
+            max_send_wr: 10,
+            max_recv_wr: 10,
+            max_send_sge: 10,
+            max_recv_sge: 10,
+            max_inline_data: DEFAULT_MAX_INLINE_DATA,
+            sq_sig_all: false,
+            timeout: 0x12,
+            retry_cnt: 6,
+            rnr_retry: 7,
+            min_rnr_timer: 0x12,
+            max_rd_atomic: 1,
+            signal_interval: 1,
+        }
+    }
+}
+
+impl QueuePairConfig {
+    pub fn validate(&self, attr: &DeviceAttr) -> io::Result<()> {
+        let device = "the device supports";
+        let protocol = "the protocol allows";
+        let limits = [
+            ("max_send_wr", self.max_send_wr, 1, attr.max_qp_wr, device),
+            ("max_recv_wr", self.max_recv_wr, 1, attr.max_qp_wr, device),
+            ("max_send_sge", self.max_send_sge, 1, attr.max_sge, device),
+            ("max_recv_sge", self.max_recv_sge, 1, attr.max_sge, device),
+            (
+                "max_rd_atomic",
+                self.max_rd_atomic.into(),
+                1,
+                attr.max_qp_init_rd_atom.min(attr.max_qp_rd_atom),
+                device,
+            ),
+            // Every WR of both queues may complete at once, the CQ has to hold them all
+            (
+                "max_send_wr + max_recv_wr",
+                self.max_send_wr.saturating_add(self.max_recv_wr),
+                2,
+                attr.max_cqe,
+                "the cq of the device holds",
+            ),
+            ("timeout", self.timeout.into(), 0, 31, protocol),
+            ("retry_cnt", self.retry_cnt.into(), 0, 7, protocol),
+            ("rnr_retry", self.rnr_retry.into(), 0, 7, protocol),
+            ("min_rnr_timer", self.min_rnr_timer.into(), 0, 31, protocol),
+            (
+                "signal_interval",
+                self.signal_interval,
+                1,
+                self.max_send_wr,
+                "max_send_wr allows",
+            ),
+        ];
+        for (name, value, min, max, source) in limits {
+            if value < min || value > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("qp {} is {}, {} {}..={}", name, value, source, min, max),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct QueuePairBuilder {
     pub pd: Arc<ProtectionDomain>,
-    event_listener: Option<EventListener>,
+    event_listener: Option<Arc<EventListener>>,
+    srq: Option<Arc<SharedReceiveQueue>>,
     qp_init_attr: QueuePairInitAttr,
+    qp_type: QueuePairType,
+    config: QueuePairConfig,
 }
 
 impl QueuePairBuilder {
@@ -80,25 +217,142 @@ impl QueuePairBuilder {
             pd: pd.clone(),
             qp_init_attr: QueuePairInitAttr::default(),
             event_listener: None,
+            srq: None,
+            qp_type: QueuePairType::Rc,
+            config: QueuePairConfig::default(),
         }
     }
 
     pub fn build(mut self) -> io::Result<QueuePair> {
-        let inner_qp = NonNull::new(unsafe {
-            rdma_sys::ibv_create_qp(
-                self.pd.as_ptr(),
-                &mut self.qp_init_attr.qp_init_attr_inner as *mut _,
-            )
-        })
-        .ok_or(io::ErrorKind::Other)?;
+        let attr = DeviceAttr::query(self.pd.ctx.as_ptr())?;
+        self.config.validate(&attr)?;
+        if let (Some(srq), Some(el)) = (&self.srq, &self.event_listener) {
+            // Receives are routed by the SRQ's listener, it has to see their completions
+            if !Arc::ptr_eq(srq.event_listener(), el) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "qp on a srq has to use the event listener of the srq",
+                ));
+            }
+        }
+        let inner_qp = match self.create_qp() {
+            Ok(inner_qp) => inner_qp,
+            // Devices do not report their inline limit, only a QP they refuse reveals it
+            Err(err) => match self.probe_max_inline_data() {
+                Some(supported) if supported < self.config.max_inline_data => {
+                    if self.config.max_inline_data != DEFAULT_MAX_INLINE_DATA {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "qp max_inline_data is {}, the device supports up to {}",
+                                self.config.max_inline_data, supported
+                            ),
+                        ));
+                    }
+                    debug!("device supports {} bytes of inline data", supported);
+                    self.config.max_inline_data = supported;
+                    self.create_qp()?
+                }
+                _ => return Err(err),
+            },
+        };
+        let cap = self.qp_init_attr.qp_init_attr_inner.cap;
+        let event_listener = self.event_listener.unwrap();
+        // Receives of a QP on a SRQ complete in the entries the SRQ reserved
+        let cq_entries = match &self.srq {
+            Some(_) => cap.max_send_wr,
+            None => cap.max_send_wr + cap.max_recv_wr,
+        };
+        if let Err(err) = event_listener.cq.reserve(cq_entries, attr.max_cqe) {
+            let errno = unsafe { ibv_destroy_qp(inner_qp.as_ptr()) };
+            assert_eq!(errno, 0);
+            return Err(err);
+        }
+        let qp_num = unsafe { (*inner_qp.as_ptr()).qp_num };
+        if let Some(srq) = &self.srq {
+            srq.register(qp_num);
+        }
         Ok(QueuePair {
             pd: self.pd.clone(),
             inner_qp,
-            event_listener: self.event_listener.unwrap(),
+            event_listener,
+            cq_entries,
+            srq: self.srq,
+            qp_type: self.qp_type,
+            qkey: AtomicU32::new(0),
+            cap,
+            config: self.config,
+            max_dest_rd_atomic: attr.max_qp_rd_atom.min(u8::MAX.into()) as u8,
+            failure: Arc::new(Failure::default()),
+            remote: Mutex::new(None),
+            watcher: Mutex::new(None),
+            uncovered: Mutex::new(vec![]),
+            send_slots: work_queue_slots(cap.max_send_wr),
+            recv_slots: work_queue_slots(cap.max_recv_wr),
+            abandoned: AtomicUsize::new(0),
+            awaited: AtomicUsize::new(0),
+            send_wrs_posted: AtomicU64::new(0),
         })
     }
 
-    pub fn set_event_listener(mut self, el: EventListener) -> Self {
+    fn create_qp(&mut self) -> io::Result<NonNull<ibv_qp>> {
+        let init_attr = &mut self.qp_init_attr.qp_init_attr_inner;
+        init_attr.cap.max_send_wr = self.config.max_send_wr;
+        init_attr.cap.max_recv_wr = self.config.max_recv_wr;
+        init_attr.cap.max_send_sge = self.config.max_send_sge;
+        init_attr.cap.max_recv_sge = self.config.max_recv_sge;
+        init_attr.cap.max_inline_data = self.config.max_inline_data;
+        init_attr.sq_sig_all = self.config.sq_sig_all.into();
+        init_attr.qp_type = self.qp_type.as_raw();
+        NonNull::new(unsafe { rdma_sys::ibv_create_qp(self.pd.as_ptr(), init_attr) }).ok_or_else(
+            || {
+                let err = io::Error::last_os_error();
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to create qp with {:?}: {}", self.config, err),
+                )
+            },
+        )
+    }
+
+    /// The inline size the device grants when asked for less than `max_inline_data`, `None`
+    /// if it refuses the QP for another reason
+    fn probe_max_inline_data(&self) -> Option<u32> {
+        let mut init_attr = self.qp_init_attr.qp_init_attr_inner;
+        let mut inline = self.config.max_inline_data;
+        while inline > 0 {
+            inline /= 2;
+            init_attr.cap = ibv_qp_cap {
+                max_inline_data: inline,
+                ..self.qp_init_attr.qp_init_attr_inner.cap
+            };
+            let qp = unsafe { rdma_sys::ibv_create_qp(self.pd.as_ptr(), &mut init_attr) };
+            if !qp.is_null() {
+                let errno = unsafe { ibv_destroy_qp(qp) };
+                assert_eq!(errno, 0);
+                return Some(init_attr.cap.max_inline_data);
+            }
+        }
+        None
+    }
+
+    pub fn set_config(mut self, config: QueuePairConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn set_qp_type(mut self, qp_type: QueuePairType) -> Self {
+        self.qp_type = qp_type;
+        self
+    }
+
+    pub fn set_srq(mut self, srq: Arc<SharedReceiveQueue>) -> Self {
+        self.qp_init_attr.qp_init_attr_inner.srq = srq.as_ptr();
+        self.srq = Some(srq);
+        self
+    }
+
+    pub fn set_event_listener(mut self, el: Arc<EventListener>) -> Self {
         self.qp_init_attr.qp_init_attr_inner.send_cq = el.cq.as_ptr();
         self.qp_init_attr.qp_init_attr_inner.recv_cq = el.cq.as_ptr();
         self.event_listener = Some(el);
@@ -113,10 +367,39 @@ pub struct QueuePairEndpoint {
     gid: Gid,
 }
 
+impl QueuePairEndpoint {
+    pub fn qp_num(&self) -> u32 {
+        self.qp_num
+    }
+}
+
 pub struct QueuePair {
     pd: Arc<ProtectionDomain>,
-    event_listener: EventListener,
+    event_listener: Arc<EventListener>,
+    srq: Option<Arc<SharedReceiveQueue>>,
+    qp_type: QueuePairType,
+    /// Set when a UD QP moves to INIT
+    qkey: AtomicU32,
     inner_qp: NonNull<ibv_qp>,
+    cap: ibv_qp_cap,
+    config: QueuePairConfig,
+    /// Incoming RDMA reads and atomics the device serves at once
+    max_dest_rd_atomic: u8,
+    /// Reserved in the CQ for the completions of this QP
+    cq_entries: u32,
+    failure: Arc<Failure>,
+    remote: Mutex<Option<QueuePairEndpoint>>,
+    watcher: Mutex<Option<JoinHandle<()>>>,
+    /// Unsignaled send WRs and their lengths not yet followed by a signaled one
+    uncovered: Mutex<Vec<(WorkRequestId, u32)>>,
+    send_slots: Arc<Semaphore>,
+    recv_slots: Arc<Semaphore>,
+    /// WRs whose operations timed out or were dropped and have not completed yet
+    abandoned: AtomicUsize,
+    /// Send operations waiting for their completion. With none of them another WR may never
+    /// follow, so a WR posted then is signaled instead of needing an empty write
+    awaited: AtomicUsize,
+    send_wrs_posted: AtomicU64,
 }
 
 impl QueuePair {
@@ -124,18 +407,114 @@ impl QueuePair {
         self.inner_qp.as_ptr()
     }
 
+    pub fn qp_num(&self) -> u32 {
+        unsafe { (*self.as_ptr()).qp_num }
+    }
+
+    pub fn qp_type(&self) -> QueuePairType {
+        self.qp_type
+    }
+
+    pub fn cap(&self) -> ibv_qp_cap {
+        self.cap
+    }
+
+    /// Sends and writes up to this many bytes are posted inline, the device may grant more than
+    /// `max_inline_data` asked for
+    pub fn inline_threshold(&self) -> usize {
+        self.cap.max_inline_data as usize
+    }
+
+    pub(crate) fn max_dest_rd_atomic(&self) -> u8 {
+        self.max_dest_rd_atomic
+    }
+
+    pub fn config(&self) -> QueuePairConfig {
+        self.config
+    }
+
+    pub fn port_num(&self) -> u8 {
+        self.pd.ctx.port_num()
+    }
+
+    pub fn active_mtu(&self) -> u32 {
+        self.pd.ctx.get_active_mtu()
+    }
+
+    /// The active path MTU in bytes
+    pub fn path_mtu_bytes(&self) -> usize {
+        mtu_bytes(self.active_mtu()) as usize
+    }
+
     pub fn endpoint(&self) -> QueuePairEndpoint {
         QueuePairEndpoint {
-            qp_num: unsafe { (*self.as_ptr()).qp_num },
+            qp_num: self.qp_num(),
+            lid: self.pd.ctx.get_lid(),
+            gid: self.pd.ctx.gid,
+        }
+    }
+
+    pub fn datagram_endpoint(&self) -> DatagramEndpoint {
+        DatagramEndpoint {
+            qp_num: self.qp_num(),
             lid: self.pd.ctx.get_lid(),
             gid: self.pd.ctx.gid,
+            qkey: self.qkey.load(Ordering::Acquire),
+        }
+    }
+
+    fn expect_connected(&self) -> io::Result<()> {
+        if self.qp_type == QueuePairType::Ud {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "qp {} is Ud, the operation needs a connected qp",
+                    self.qp_num()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn expect_type(&self, qp_type: QueuePairType) -> io::Result<()> {
+        if self.qp_type != qp_type {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "qp {} is {:?}, the operation needs {:?}",
+                    self.qp_num(),
+                    self.qp_type,
+                    qp_type
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn modify(&self, attr: &mut ibv_qp_attr, mask: i32) -> io::Result<()> {
+        if mask == 0 {
+            return Ok(());
+        }
+        let errno = unsafe { ibv_modify_qp(self.as_ptr(), attr, mask) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
         }
+        Ok(())
     }
 
-    pub fn modify_to_init(&self, flag: ibv_access_flags) -> io::Result<()> {
+    fn modify_to_init(&self, flag: ibv_access_flags) -> io::Result<()> {
+        self.expect_connected()?;
+        // UC has no responder side for reads and atomics
+        let flag = match self.qp_type {
+            QueuePairType::Uc => {
+                flag & (ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+                    | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE)
+            }
+            _ => flag,
+        };
         let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         attr.pkey_index = 0;
-        attr.port_num = 1;
+        attr.port_num = self.port_num();
         attr.qp_state = ibv_qp_state::IBV_QPS_INIT;
         attr.qp_access_flags = flag.0;
         let flags = ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
@@ -149,16 +528,18 @@ impl QueuePair {
         Ok(())
     }
 
-    pub fn modify_to_rtr(
+    fn modify_to_rtr(
         &self,
         remote: QueuePairEndpoint,
+        path_mtu: u32,
         start_psn: u32,
         max_dest_rd_atomic: u8,
         min_rnr_timer: u8,
     ) -> io::Result<()> {
+        self.expect_connected()?;
         let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         attr.qp_state = ibv_qp_state::IBV_QPS_RTR;
-        attr.path_mtu = self.pd.ctx.get_active_mtu();
+        attr.path_mtu = path_mtu;
         attr.dest_qp_num = remote.qp_num;
         attr.rq_psn = start_psn;
         attr.max_dest_rd_atomic = max_dest_rd_atomic;
@@ -167,18 +548,20 @@ impl QueuePair {
         attr.ah_attr.sl = 0;
         attr.ah_attr.src_path_bits = 0;
         attr.ah_attr.is_global = 1;
-        attr.ah_attr.port_num = 1;
+        attr.ah_attr.port_num = self.port_num();
         attr.ah_attr.grh.dgid = remote.gid.into();
         attr.ah_attr.grh.hop_limit = 0xff;
-        //TODO: Configurable sgid_index
-        attr.ah_attr.grh.sgid_index = 1;
-        let flags = ibv_qp_attr_mask::IBV_QP_STATE
+        attr.ah_attr.grh.sgid_index = self.pd.ctx.gid_index();
+        let mut flags = ibv_qp_attr_mask::IBV_QP_STATE
             | ibv_qp_attr_mask::IBV_QP_AV
             | ibv_qp_attr_mask::IBV_QP_PATH_MTU
             | ibv_qp_attr_mask::IBV_QP_DEST_QPN
-            | ibv_qp_attr_mask::IBV_QP_RQ_PSN
-            | ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
-            | ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
+            | ibv_qp_attr_mask::IBV_QP_RQ_PSN;
+        if self.qp_type == QueuePairType::Rc {
+            flags = flags
+                | ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
+                | ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
+        }
         let errno = unsafe { ibv_modify_qp(self.as_ptr(), &mut attr, flags.0 as _) };
         debug!(
             "modify qp to rtr, err info : {:?}",
@@ -190,7 +573,7 @@ impl QueuePair {
         Ok(())
     }
 
-    pub fn modify_to_rts(
+    fn modify_to_rts(
         &self,
         timeout: u8,
         retry_cnt: u8,
@@ -198,6 +581,7 @@ impl QueuePair {
         start_psn: u32,
         max_rd_atomic: u8,
     ) -> io::Result<()> {
+        self.expect_connected()?;
         let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
         attr.qp_state = ibv_qp_state::IBV_QPS_RTS;
         attr.timeout = timeout;
@@ -205,12 +589,18 @@ impl QueuePair {
         attr.rnr_retry = rnr_retry;
         attr.sq_psn = start_psn;
         attr.max_rd_atomic = max_rd_atomic;
-        let flags = ibv_qp_attr_mask::IBV_QP_STATE
-            | ibv_qp_attr_mask::IBV_QP_TIMEOUT
-            | ibv_qp_attr_mask::IBV_QP_RETRY_CNT
-            | ibv_qp_attr_mask::IBV_QP_RNR_RETRY
-            | ibv_qp_attr_mask::IBV_QP_SQ_PSN
-            | ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC;
+        // UC neither retransmits nor issues reads, only the PSN applies
+        let flags = match self.qp_type {
+            QueuePairType::Rc => {
+                ibv_qp_attr_mask::IBV_QP_STATE
+                    | ibv_qp_attr_mask::IBV_QP_TIMEOUT
+                    | ibv_qp_attr_mask::IBV_QP_RETRY_CNT
+                    | ibv_qp_attr_mask::IBV_QP_RNR_RETRY
+                    | ibv_qp_attr_mask::IBV_QP_SQ_PSN
+                    | ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC
+            }
+            _ => ibv_qp_attr_mask::IBV_QP_STATE | ibv_qp_attr_mask::IBV_QP_SQ_PSN,
+        };
         let errno = unsafe { ibv_modify_qp(self.as_ptr(), &mut attr, flags.0 as _) };
         if errno != 0 {
             return Err(io::Error::from_raw_os_error(errno));
@@ -218,203 +608,1784 @@ impl QueuePair {
         Ok(())
     }
 
-    fn submit_send(&self, lms: Vec<&LocalMemoryRegion>, wr_id: WorkRequestId) -> io::Result<()> {
-        let mut bad_wr = std::ptr::null_mut::<ibv_send_wr>();
-        let mut sr = SendWr::new_send(lms, wr_id);
-        self.event_listener.cq.req_notify(false).unwrap();
-        let errno = unsafe { ibv_post_send(self.as_ptr(), sr.as_mut(), &mut bad_wr) };
-        if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
-        }
+    fn modify_to_init_ud(&self, qkey: u32) -> io::Result<()> {
+        self.expect_type(QueuePairType::Ud)?;
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.pkey_index = 0;
+        attr.port_num = self.port_num();
+        attr.qp_state = ibv_qp_state::IBV_QPS_INIT;
+        attr.qkey = qkey;
+        let flags = ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
+            | ibv_qp_attr_mask::IBV_QP_STATE
+            | ibv_qp_attr_mask::IBV_QP_PORT
+            | ibv_qp_attr_mask::IBV_QP_QKEY;
+        self.modify(&mut attr, flags.0 as _)?;
+        self.qkey.store(qkey, Ordering::Release);
         Ok(())
     }
 
-    fn submit_receive(&self, lms: Vec<&LocalMemoryRegion>, wr_id: WorkRequestId) -> io::Result<()> {
-        let mut rr = RecvWr::new_recv(lms, wr_id);
-        let mut bad_wr = std::ptr::null_mut::<ibv_recv_wr>();
-        self.event_listener.cq.req_notify(false).unwrap();
-        let errno = unsafe { ibv_post_recv(self.as_ptr(), rr.as_mut(), &mut bad_wr) };
-        if errno != 0 {
-            return Err(io::Error::from_raw_os_error(errno));
-        }
-        Ok(())
+    /// A UD QP has no remote, RTR and RTS only need the state and the send PSN
+    fn modify_to_rtr_ud(&self) -> io::Result<()> {
+        self.expect_type(QueuePairType::Ud)?;
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = ibv_qp_state::IBV_QPS_RTR;
+        self.modify(&mut attr, ibv_qp_attr_mask::IBV_QP_STATE.0 as _)
     }
 
-    fn submit_read(
+    fn modify_to_rts_ud(&self, start_psn: u32) -> io::Result<()> {
+        self.expect_type(QueuePairType::Ud)?;
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = ibv_qp_state::IBV_QPS_RTS;
+        attr.sq_psn = start_psn;
+        let flags = ibv_qp_attr_mask::IBV_QP_STATE | ibv_qp_attr_mask::IBV_QP_SQ_PSN;
+        self.modify(&mut attr, flags.0 as _)
+    }
+
+    /// Creates another QP on the same PD, CQ and SRQ
+    pub(crate) fn new_sibling(
         &self,
-        lms: Vec<&LocalMemoryRegion>,
-        rm: &RemoteMemoryRegion,
-        wr_id: WorkRequestId,
-    ) -> io::Result<()> {
-        let mut bad_wr = std::ptr::null_mut::<ibv_send_wr>();
-        let mut sr = SendWr::new_read(lms, wr_id, rm);
-        self.event_listener.cq.req_notify(false).unwrap();
-        let errno = unsafe { ibv_post_send(self.as_ptr(), sr.as_mut(), &mut bad_wr) };
+        access: ibv_access_flags,
+    ) -> io::Result<TypedQueuePair<state::Init>> {
+        let mut builder = self
+            .pd
+            .create_queue_pair_builder()
+            .set_qp_type(self.qp_type)
+            .set_event_listener(self.event_listener.clone())
+            .set_config(self.config);
+        if let Some(srq) = &self.srq {
+            builder = builder.set_srq(srq.clone());
+        }
+        TypedQueuePair::reset(Arc::new(builder.build()?))?.into_init(access)
+    }
+
+    pub fn srq(&self) -> Option<&Arc<SharedReceiveQueue>> {
+        self.srq.as_ref()
+    }
+
+    pub fn query_state(&self) -> io::Result<QueuePairState> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        let mut init_attr = unsafe { std::mem::zeroed::<ibv_qp_init_attr>() };
+        let errno = unsafe {
+            ibv_query_qp(
+                self.as_ptr(),
+                &mut attr,
+                ibv_qp_attr_mask::IBV_QP_STATE.0 as _,
+                &mut init_attr,
+            )
+        };
         if errno != 0 {
             return Err(io::Error::from_raw_os_error(errno));
         }
-        Ok(())
+        Ok(QueuePairState::from(attr.qp_state))
     }
 
-    fn submit_write(
-        &self,
-        lms: Vec<&LocalMemoryRegion>,
-        rm: &RemoteMemoryRegion,
-        wr_id: WorkRequestId,
-    ) -> io::Result<()> {
-        let mut bad_wr = std::ptr::null_mut::<ibv_send_wr>();
-        let mut sr = SendWr::new_write(lms, wr_id, rm);
-        self.event_listener.cq.req_notify(false).unwrap();
-        let errno = unsafe { ibv_post_send(self.as_ptr(), sr.as_mut(), &mut bad_wr) };
+    /// The peer endpoint of the last handshake
+    pub fn remote(&self) -> Option<QueuePairEndpoint> {
+        *self.remote.lock().unwrap()
+    }
+
+    fn modify_to_error(&self) -> io::Result<()> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = ibv_qp_state::IBV_QPS_ERR;
+        let flags = ibv_qp_attr_mask::IBV_QP_STATE;
+        let errno = unsafe { ibv_modify_qp(self.as_ptr(), &mut attr, flags.0 as _) };
         if errno != 0 {
             return Err(io::Error::from_raw_os_error(errno));
         }
         Ok(())
     }
 
-    pub fn send_sge(self: &Arc<Self>, lms: Vec<&LocalMemoryRegion>) -> QueuePairOps<QPSend> {
-        let send = QPSend::new(lms);
-        QueuePairOps::new(self.clone(), send)
+    pub(crate) fn shutdown(&self) -> io::Result<()> {
+        let ans = self.modify_to_error();
+        self.stop_watching();
+        // The listener of a SRQ serves every QP attached to it
+        if self.srq.is_none() {
+            self.event_listener.stop();
+        }
+        ans
     }
 
-    pub fn receive_sge(self: &Arc<Self>, lms: Vec<&LocalMemoryRegion>) -> QueuePairOps<QPRecv> {
-        let recv = QPRecv::new(lms);
-        QueuePairOps::new(self.clone(), recv)
+    /// Fails the pending and future operations of this QP
+    pub(crate) fn fail(&self, event: AsyncEvent) {
+        debug!("qp {} failed by async event {:?}", self.qp_num(), event);
+        self.fail_with(format!("queue pair failed due to async event {:?}", event));
     }
 
-    pub async fn read_sge(
-        &self,
-        lms: Vec<&LocalMemoryRegion>,
-        rm: &RemoteMemoryRegion,
-    ) -> Result<(), WCError> {
-        let (wr_id, mut resp_rx) = self.event_listener.register();
-        let len: usize = lms.iter().map(|lm| lm.length()).sum();
-        self.submit_read(lms, rm, wr_id).unwrap();
-        resp_rx
-            .recv()
-            .await
-            .unwrap()
-            .err()
-            .map(|sz| assert_eq!(sz, len))
+    /// Moves the QP to the error state so that pending operations end with the flush
+    /// completions of their WRs. If it can not be moved they fail at once and leave their
+    /// buffers to the QP, the device may still use them
+    fn fail_with(&self, reason: String) {
+        match self.modify_to_error() {
+            Ok(()) => self.failure.fail_flushed(reason),
+            Err(err) => {
+                debug!("failed to move qp to error state: {}", err);
+                self.failure.fail(reason);
+            }
+        }
     }
 
-    pub async fn write_sge(
-        &self,
-        lms: Vec<&LocalMemoryRegion>,
-        rm: &RemoteMemoryRegion,
-    ) -> Result<(), WCError> {
-        let (wr_id, mut resp_rx) = self.event_listener.register();
-        let len: usize = lms.iter().map(|lm| lm.length()).sum();
-        self.submit_write(lms, rm, wr_id).unwrap();
-        resp_rx
-            .recv()
-            .await
-            .unwrap()
-            .err()
-            .map(|sz| assert_eq!(sz, len))
+    fn is_fatal(&self, event: AsyncEvent) -> bool {
+        match event {
+            AsyncEvent::QpFatal { qp_num }
+            | AsyncEvent::QpRequestError { qp_num }
+            | AsyncEvent::QpAccessError { qp_num } => qp_num == self.qp_num(),
+            AsyncEvent::CqError { cq_handle } => cq_handle == self.event_listener.cq.handle(),
+            AsyncEvent::PortError { port_num } => port_num == self.port_num(),
+            AsyncEvent::DeviceFatal => true,
+            _ => false,
+        }
     }
 
-    pub fn send(self: &Arc<Self>, lm: &LocalMemoryRegion) -> QueuePairOps<QPSend> {
-        self.send_sge(vec![lm])
+    /// Fails this QP once an async event reports it unusable, replacing the previous watcher
+    pub(crate) fn watch_async_events(self: &Arc<Self>) {
+        let mut events = self.pd.ctx.async_events();
+        let qp = Arc::downgrade(self);
+        let watcher = tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let qp = match qp.upgrade() {
+                    Some(qp) => qp,
+                    None => return,
+                };
+                if qp.is_fatal(event) {
+                    qp.fail(event);
+                    return;
+                }
+            }
+        });
+        if let Some(old) = self.watcher.lock().unwrap().replace(watcher) {
+            old.abort();
+        }
     }
 
-    pub fn receive(self: &Arc<Self>, lm: &LocalMemoryRegion) -> QueuePairOps<QPRecv> {
-        self.receive_sge(vec![lm])
+    fn stop_watching(&self) {
+        if let Some(watcher) = self.watcher.lock().unwrap().take() {
+            watcher.abort();
+        }
     }
 
-    pub async fn read(
-        &self,
-        lm: &mut LocalMemoryRegion,
-        rm: &RemoteMemoryRegion,
-    ) -> Result<(), WCError> {
-        self.read_sge(vec![lm], rm).await
+    /// With a signal interval of 1, a UD QP or `sq_sig_all` every WR is signaled
+    fn selective_signaling(&self) -> bool {
+        self.config.signal_interval > 1
+            && !self.config.sq_sig_all
+            && self.qp_type != QueuePairType::Ud
     }
 
-    pub async fn write(
-        &self,
-        lm: &LocalMemoryRegion,
-        rm: &RemoteMemoryRegion,
-    ) -> Result<(), WCError> {
-        self.write_sge(vec![lm], rm).await
+    fn post_send(&self, sr: &mut SendWr) -> io::Result<()> {
+        let mut uncovered = self.uncovered.lock().unwrap();
+        if self.selective_signaling()
+            && self.awaited.load(Ordering::Acquire) > 0
+            && uncovered.len() + 1 < self.config.signal_interval as usize
+        {
+            sr.set_unsignaled();
+        }
+        self.post_send_chain(&mut uncovered, std::slice::from_mut(sr))
+            .map_err(|(_, err)| err)
     }
-}
 
-unsafe impl Sync for QueuePair {}
-
-unsafe impl Send for QueuePair {}
-
-impl Drop for QueuePair {
-    fn drop(&mut self) {
-        let errno = unsafe { ibv_destroy_qp(self.as_ptr()) };
-        assert_eq!(errno, 0);
+    /// Chains `srs` and posts them with one doorbell, only the last one is signaled. On failure
+    /// returns how many WRs made it to the device, those still complete
+    fn post_send_batch(&self, srs: &mut [SendWr]) -> Result<(), (usize, io::Error)> {
+        let mut uncovered = self.uncovered.lock().unwrap();
+        if self.qp_type != QueuePairType::Ud {
+            let last = srs.len() - 1;
+            srs[..last].iter_mut().for_each(SendWr::set_unsignaled);
+        }
+        self.post_send_chain(&mut uncovered, srs)
     }
-}
-
-pub trait QueuePairOp {
-    type Output;
-
-    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()>;
-
-    fn parse_wc(&self, wc: WorkCompletion) -> Self::Output;
-}
 
-pub struct QPSend<'lm> {
-    lms: Vec<&'lm LocalMemoryRegion>,
-    len: usize,
-}
+    /// Moves the uncovered WRs to the signaled WRs of `srs` that come after them
+    fn plan_covers(
+        uncovered: &mut Vec<(WorkRequestId, u32)>,
+        srs: &[SendWr],
+    ) -> Vec<(WorkRequestId, Vec<(WorkRequestId, u32)>)> {
+        let mut covers = vec![];
+        for sr in srs {
+            if sr.is_signaled() {
+                covers.push((sr.wr_id(), mem::take(uncovered)));
+            } else {
+                uncovered.push((sr.wr_id(), sr.byte_len()));
+            }
+        }
+        covers
+    }
 
-impl<'lm> QPSend<'lm> {
-    fn new(lms: Vec<&'lm LocalMemoryRegion>) -> Self {
-        Self {
-            len: lms.iter().map(|lm| lm.length()).sum(),
-            lms,
+    /// Callers hold the `uncovered` lock, so covers follow the order of the send queue
+    fn post_send_chain(
+        &self,
+        uncovered: &mut Vec<(WorkRequestId, u32)>,
+        srs: &mut [SendWr],
+    ) -> Result<(), (usize, io::Error)> {
+        let before = uncovered.clone();
+        for (signaled, covered) in Self::plan_covers(uncovered, srs) {
+            if !covered.is_empty() {
+                self.event_listener.cover(signaled, covered);
+            }
+        }
+        for i in 1..srs.len() {
+            let next: *mut ibv_send_wr = srs[i].as_mut();
+            srs[i - 1].as_mut().next = next;
         }
+        let mut bad_wr = std::ptr::null_mut::<ibv_send_wr>();
+        self.event_listener.cq.req_notify(false).unwrap();
+        let errno = unsafe { ibv_post_send(self.as_ptr(), srs[0].as_mut(), &mut bad_wr) };
+        if errno != 0 {
+            let posted = srs
+                .iter()
+                .position(|sr| ptr::eq(sr.as_ref(), bad_wr))
+                .unwrap_or(0);
+            // The rejected WRs never complete, the WRs they covered wait for the next signal
+            for sr in srs[posted..].iter().filter(|sr| sr.is_signaled()) {
+                self.event_listener.uncover(sr.wr_id());
+            }
+            *uncovered = before;
+            let _ = Self::plan_covers(uncovered, &srs[..posted]);
+            let _ = self
+                .send_wrs_posted
+                .fetch_add(posted as u64, Ordering::Relaxed);
+            return Err((posted, io::Error::from_raw_os_error(errno)));
+        }
+        let _ = self
+            .send_wrs_posted
+            .fetch_add(srs.len() as u64, Ordering::Relaxed);
+        Ok(())
     }
-}
 
-impl<'lm> QueuePairOp for QPSend<'lm> {
-    type Output = Result<(), WCError>;
+    fn slots(&self, receive: bool) -> &Arc<Semaphore> {
+        if receive {
+            &self.recv_slots
+        } else {
+            &self.send_slots
+        }
+    }
 
-    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
-        qp.submit_send(self.lms.to_owned(), wr_id)
+    fn is_uncovered(&self, wr_id: WorkRequestId) -> bool {
+        let uncovered = self.uncovered.lock().unwrap();
+        uncovered.iter().any(|(id, _)| *id == wr_id)
     }
 
-    fn parse_wc(&self, wc: WorkCompletion) -> Self::Output {
-        wc.err().map(|sz| assert_eq!(sz, self.len))
+    /// Posts an empty signaled write on `slot` that completes the unsignaled WRs in front of
+    /// it. If even that fails the QP is failed, see `fail_with`
+    fn signal_uncovered(&self, slot: OwnedSemaphorePermit) {
+        let mut uncovered = self.uncovered.lock().unwrap();
+        if uncovered.is_empty() {
+            return;
+        }
+        let (wr_id, mut recv) = self.event_listener.register();
+        let mut sr = SendWr::new_empty_write(wr_id);
+        match self.post_send_chain(&mut uncovered, std::slice::from_mut(&mut sr)) {
+            Ok(()) => {
+                let failure = self.failure.clone();
+                let generation = failure.generation();
+                let _ = tokio::spawn(async move {
+                    // A reset discards the write together with its completion
+                    future::poll_fn(|cx| match recv.poll_recv(cx) {
+                        Poll::Ready(_) => Poll::Ready(()),
+                        Poll::Pending => failure.poll_reset(wr_id, generation, cx),
+                    })
+                    .await;
+                    failure.unwatch(wr_id);
+                    drop(slot);
+                });
+            }
+            Err((_, err)) => {
+                self.event_listener.unregister(wr_id);
+                self.fail_with(format!(
+                    "failed to signal unsignaled work requests: {}",
+                    err
+                ));
+            }
+        }
     }
-}
 
-pub struct QPRecv<'lm> {
-    lms: Vec<&'lm LocalMemoryRegion>,
-}
+    /// WRs of timed out or dropped operations still on the device, their buffers are held until
+    /// they complete
+    pub fn abandoned_in_flight(&self) -> usize {
+        self.abandoned.load(Ordering::Acquire)
+    }
 
-impl<'lm> QPRecv<'lm> {
-    fn new(lms: Vec<&'lm LocalMemoryRegion>) -> Self {
-        Self { lms }
+    /// Send WRs posted so far, empty writes that signal unsignaled ones included
+    pub fn send_wrs_posted(&self) -> u64 {
+        self.send_wrs_posted.load(Ordering::Relaxed)
     }
-}
 
-impl<'lm> QueuePairOp for QPRecv<'lm> {
-    type Output = Result<usize, WCError>;
+    /// Completions taken from the CQ of this QP so far, shared QPs count each other's
+    pub fn completions_polled(&self) -> u64 {
+        self.event_listener.cq.polled()
+    }
 
-    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
-        qp.submit_receive(self.lms.to_owned(), wr_id)
+    /// Keeps the buffers and the slot of an abandoned WR until its completion arrives, the
+    /// device may still be reading or writing them
+    fn track_abandoned(
+        self: &Arc<Self>,
+        wr_id: WorkRequestId,
+        generation: u64,
+        mut recv: mpsc::Receiver<WorkCompletion>,
+        slot: OwnedSemaphorePermit,
+        retained: Retained,
+    ) {
+        self.failure.unwatch(wr_id);
+        let handle = match Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => {
+                // Nothing can wait for the completion, leaking is the only safe way out
+                mem::forget(slot);
+                retained.leak();
+                return;
+            }
+        };
+        // Weak, a destroyed QP no longer touches the buffers and must not be kept around for them
+        let qp = Arc::downgrade(self);
+        let _ = self.abandoned.fetch_add(1, Ordering::AcqRel);
+        let _ = handle.spawn(async move {
+            let mut acquire = SlotAcquire::default();
+            future::poll_fn(|cx| {
+                if recv.poll_recv(cx).is_ready() {
+                    return Poll::Ready(());
+                }
+                let qp = match qp.upgrade() {
+                    Some(qp) => qp,
+                    None => return Poll::Pending,
+                };
+                // The device is done with the buffers of a WR discarded by a reset
+                if qp.failure.poll_reset(wr_id, generation, cx).is_ready() {
+                    return Poll::Ready(());
+                }
+                // Nobody else is left to signal it
+                if qp.is_uncovered(wr_id) {
+                    if let Poll::Ready(slot) = acquire.poll_acquire(&qp.send_slots, 1, cx) {
+                        qp.signal_uncovered(slot);
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            if let Some(qp) = qp.upgrade() {
+                qp.failure.unwatch(wr_id);
+                let _ = qp.abandoned.fetch_sub(1, Ordering::AcqRel);
+            }
+            // After the count, a reclaimed buffer is never seen with its WR still counted
+            drop((slot, retained));
+        });
     }
 
-    fn parse_wc(&self, wc: WorkCompletion) -> Self::Output {
-        wc.err()
+    fn send_wr(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        imm: Option<u32>,
+        wr_id: WorkRequestId,
+    ) -> SendWr {
+        let len: usize = lms.iter().map(|lm| lm.length()).sum();
+        let mut sr = match imm {
+            Some(imm) => SendWr::new_send_with_imm(lms, wr_id, imm),
+            None => SendWr::new_send(lms, wr_id),
+        };
+        if len <= self.inline_threshold() {
+            sr.set_inline();
+        }
+        sr
     }
-}
 
-enum QueuePairOpsState {
-    Init,
-    Submitted(mpsc::Receiver<WorkCompletion>),
-}
+    fn read_wr(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        rm: &RemoteMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<SendWr> {
+        if self.qp_type != QueuePairType::Rc {
+            return Err(self.qp_type.unsupported("rdma read"));
+        }
+        Ok(SendWr::new_read(lms, wr_id, rm))
+    }
 
-pub struct QueuePairOps<Op: QueuePairOp + Unpin> {
+    fn write_wr(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        rm: &RemoteMemoryRegion,
+        imm: Option<u32>,
+        wr_id: WorkRequestId,
+    ) -> io::Result<SendWr> {
+        if self.qp_type == QueuePairType::Ud {
+            return Err(self.qp_type.unsupported("rdma write"));
+        }
+        let len: usize = lms.iter().map(|lm| lm.length()).sum();
+        let mut sr = match imm {
+            Some(imm) => SendWr::new_write_with_imm(lms, wr_id, rm, imm),
+            None => SendWr::new_write(lms, wr_id, rm),
+        };
+        if len <= self.inline_threshold() {
+            sr.set_inline();
+        }
+        Ok(sr)
+    }
+
+    fn submit_send(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        imm: Option<u32>,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        self.post_send(&mut self.send_wr(lms, imm, wr_id))
+    }
+
+    fn submit_receive(&self, lms: Vec<&LocalMemoryRegion>, wr_id: WorkRequestId) -> io::Result<()> {
+        if let Some(srq) = &self.srq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("qp {} receives through srq {}", self.qp_num(), srq.handle()),
+            ));
+        }
+        let mut rr = RecvWr::new_recv(lms, wr_id);
+        let mut bad_wr = std::ptr::null_mut::<ibv_recv_wr>();
+        self.event_listener.cq.req_notify(false).unwrap();
+        let errno = unsafe { ibv_post_recv(self.as_ptr(), rr.as_mut(), &mut bad_wr) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        Ok(())
+    }
+
+    fn submit_send_to(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        ah: &AddressHandle,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        self.expect_type(QueuePairType::Ud)?;
+        let len: usize = lms.iter().map(|lm| lm.length()).sum();
+        let mtu = self.path_mtu_bytes();
+        if len > mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "datagram of {} bytes exceeds the path mtu of {} bytes",
+                    len, mtu
+                ),
+            ));
+        }
+        self.post_send(&mut SendWr::new_send_to(lms, wr_id, ah))
+    }
+
+    fn submit_read(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        rm: &RemoteMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        self.post_send(&mut self.read_wr(lms, rm, wr_id)?)
+    }
+
+    fn submit_atomic(
+        &self,
+        lm: &LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+        op: AtomicOp,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        if self.qp_type != QueuePairType::Rc {
+            return Err(self.qp_type.unsupported("remote atomic"));
+        }
+        check_atomic_target(lm, rm)?;
+        let mut sr = match op {
+            AtomicOp::CompareAndSwap { expected, new } => {
+                SendWr::new_compare_swap(lm, wr_id, rm, expected, new)
+            }
+            AtomicOp::FetchAndAdd { delta } => SendWr::new_fetch_add(lm, wr_id, rm, delta),
+        };
+        self.post_send(&mut sr)
+    }
+
+    fn submit_write(
+        &self,
+        lms: Vec<&LocalMemoryRegion>,
+        rm: &RemoteMemoryRegion,
+        imm: Option<u32>,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        self.post_send(&mut self.write_wr(lms, rm, imm, wr_id)?)
+    }
+
+    /// Posts `data` itself instead of a memory region, it can be reused as soon as this returns
+    fn submit_inline(
+        &self,
+        data: &[u8],
+        rm: Option<&RemoteMemoryRegion>,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        self.expect_connected()?;
+        if data.len() > self.inline_threshold() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes exceed the inline threshold {} of qp {}",
+                    data.len(),
+                    self.inline_threshold(),
+                    self.qp_num()
+                ),
+            ));
+        }
+        let mut sr = match rm {
+            Some(rm) => SendWr::new_write_inline(data, wr_id, rm),
+            None => SendWr::new_send_inline(data, wr_id),
+        };
+        self.post_send(&mut sr)
+    }
+
+    /// Waits for the completion of `wr_id` unless the QP fails first
+    async fn wait_completion(
+        &self,
+        wr_id: WorkRequestId,
+        generation: u64,
+        recv: &mut mpsc::Receiver<WorkCompletion>,
+    ) -> io::Result<WorkCompletion> {
+        let ans = future::poll_fn(|cx| match recv.poll_recv(cx) {
+            Poll::Ready(Some(wc)) => Poll::Ready(Ok(wc)),
+            Poll::Ready(None) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "completion queue poller stopped",
+            ))),
+            Poll::Pending => self.failure.poll(wr_id, generation, cx).map(Err),
+        })
+        .await;
+        self.failure.unwatch(wr_id);
+        ans
+    }
+
+    /// Collects sends, reads and writes to post them with a single `ibv_post_send`
+    pub fn batch<'a>(self: &Arc<Self>) -> SendBatch<'a> {
+        SendBatch {
+            qp: self.clone(),
+            ops: vec![],
+            _guard: None,
+        }
+    }
+
+    pub fn send_sge(self: &Arc<Self>, lms: Vec<&LocalMemoryRegion>) -> QueuePairOps<QPSend> {
+        let send = QPSend::new(lms, None);
+        QueuePairOps::new(self.clone(), send)
+    }
+
+    /// The receiver gets `imm` in `Received::Send` next to the payload
+    pub fn send_sge_with_imm(
+        self: &Arc<Self>,
+        lms: Vec<&LocalMemoryRegion>,
+        imm: u32,
+    ) -> QueuePairOps<QPSend> {
+        let send = QPSend::new(lms, Some(imm));
+        QueuePairOps::new(self.clone(), send)
+    }
+
+    pub fn receive_sge(self: &Arc<Self>, lms: Vec<&LocalMemoryRegion>) -> QueuePairOps<QPRecv> {
+        let recv = QPRecv::new(lms);
+        QueuePairOps::new(self.clone(), recv)
+    }
+
+    pub fn read_sge<'a>(
+        self: &Arc<Self>,
+        lms: Vec<&'a LocalMemoryRegion>,
+        rm: &'a RemoteMemoryRegion,
+    ) -> QueuePairOps<QPRead<'a>> {
+        let read = QPRead::new(lms, rm);
+        QueuePairOps::new(self.clone(), read)
+    }
+
+    pub fn write_sge<'a>(
+        self: &Arc<Self>,
+        lms: Vec<&'a LocalMemoryRegion>,
+        rm: &'a RemoteMemoryRegion,
+    ) -> QueuePairOps<QPWrite<'a>> {
+        let write = QPWrite::new(lms, rm, None);
+        QueuePairOps::new(self.clone(), write)
+    }
+
+    /// The immediate consumes a receive on the remote QP and shows up in its completion
+    pub fn write_with_imm<'a>(
+        self: &Arc<Self>,
+        lm: &'a LocalMemoryRegion,
+        rm: &'a RemoteMemoryRegion,
+        imm: u32,
+    ) -> QueuePairOps<QPWrite<'a>> {
+        let write = QPWrite::new(vec![lm], rm, Some(imm));
+        QueuePairOps::new(self.clone(), write)
+    }
+
+    /// Swaps the 8 bytes at `rm` to `new` if they equal `expected`, the previous value lands in `lm`
+    pub fn compare_and_swap<'a>(
+        self: &Arc<Self>,
+        lm: &'a LocalMemoryRegion,
+        rm: &'a RemoteMemoryRegion,
+        expected: u64,
+        new: u64,
+    ) -> QueuePairOps<QPAtomic<'a>> {
+        let atomic = QPAtomic::new(lm, rm, AtomicOp::CompareAndSwap { expected, new });
+        QueuePairOps::new(self.clone(), atomic)
+    }
+
+    /// Adds `delta` to the 8 bytes at `rm`, the previous value lands in `lm`
+    pub fn fetch_and_add<'a>(
+        self: &Arc<Self>,
+        lm: &'a LocalMemoryRegion,
+        rm: &'a RemoteMemoryRegion,
+        delta: u64,
+    ) -> QueuePairOps<QPAtomic<'a>> {
+        let atomic = QPAtomic::new(lm, rm, AtomicOp::FetchAndAdd { delta });
+        QueuePairOps::new(self.clone(), atomic)
+    }
+
+    /// `data` must not exceed `inline_threshold`
+    pub fn send_inline<'a>(self: &Arc<Self>, data: &'a [u8]) -> QueuePairOps<QPInline<'a>> {
+        QueuePairOps::new(self.clone(), QPInline::new(data, None))
+    }
+
+    /// `data` must not exceed `inline_threshold`
+    pub fn write_inline<'a>(
+        self: &Arc<Self>,
+        data: &'a [u8],
+        rm: &'a RemoteMemoryRegion,
+    ) -> QueuePairOps<QPInline<'a>> {
+        QueuePairOps::new(self.clone(), QPInline::new(data, Some(rm)))
+    }
+
+    pub fn send(self: &Arc<Self>, lm: &LocalMemoryRegion) -> QueuePairOps<QPSend> {
+        self.send_sge(vec![lm])
+    }
+
+    pub fn send_with_imm(
+        self: &Arc<Self>,
+        lm: &LocalMemoryRegion,
+        imm: u32,
+    ) -> QueuePairOps<QPSend> {
+        self.send_sge_with_imm(vec![lm], imm)
+    }
+
+    pub fn receive(self: &Arc<Self>, lm: &LocalMemoryRegion) -> QueuePairOps<QPRecv> {
+        self.receive_sge(vec![lm])
+    }
+
+    pub fn send_to<'a>(
+        self: &Arc<Self>,
+        ah: &'a AddressHandle,
+        lm: &'a LocalMemoryRegion,
+    ) -> QueuePairOps<QPSendTo<'a>> {
+        QueuePairOps::new(self.clone(), QPSendTo::new(vec![lm], ah))
+    }
+
+    /// `lm` has to leave room for the GRH in its first `GRH_LEN` bytes, the payload follows
+    pub fn recv_from<'a>(
+        self: &Arc<Self>,
+        lm: &'a LocalMemoryRegion,
+    ) -> QueuePairOps<QPRecvFrom<'a>> {
+        QueuePairOps::new(self.clone(), QPRecvFrom::new(lm))
+    }
+
+    pub fn read<'a>(
+        self: &Arc<Self>,
+        lm: &'a mut LocalMemoryRegion,
+        rm: &'a RemoteMemoryRegion,
+    ) -> QueuePairOps<QPRead<'a>> {
+        self.read_sge(vec![lm], rm)
+    }
+
+    pub fn write<'a>(
+        self: &Arc<Self>,
+        lm: &'a LocalMemoryRegion,
+        rm: &'a RemoteMemoryRegion,
+    ) -> QueuePairOps<QPWrite<'a>> {
+        self.write_sge(vec![lm], rm)
+    }
+
+    /// Takes `lm` and hands it back with the result. Unlike `send`, dropping the future or
+    /// timing out while the WR is in flight leaves the buffer with the WR until it completes
+    pub fn send_owned(self: &Arc<Self>, lm: LocalMemoryRegion) -> OwnedOps<OwnedSend> {
+        OwnedOps::new(self.clone(), lm, OwnedSend)
+    }
+
+    /// See `send_owned`
+    pub fn receive_owned(self: &Arc<Self>, lm: LocalMemoryRegion) -> OwnedOps<OwnedRecv> {
+        OwnedOps::new(self.clone(), lm, OwnedRecv)
+    }
+
+    /// See `send_owned`
+    pub fn read_owned(
+        self: &Arc<Self>,
+        lm: LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+    ) -> OwnedOps<OwnedRead> {
+        OwnedOps::new(self.clone(), lm, OwnedRead { rm: rm.retain() })
+    }
+
+    /// See `send_owned`
+    pub fn write_owned(
+        self: &Arc<Self>,
+        lm: LocalMemoryRegion,
+        rm: &RemoteMemoryRegion,
+    ) -> OwnedOps<OwnedWrite> {
+        OwnedOps::new(self.clone(), lm, OwnedWrite { rm: rm.retain() })
+    }
+}
+
+/// The result of an operation and the buffer it took
+pub type BufResult<T, B> = (io::Result<T>, B);
+
+unsafe impl Sync for QueuePair {}
+
+unsafe impl Send for QueuePair {}
+
+impl Drop for QueuePair {
+    fn drop(&mut self) {
+        self.stop_watching();
+        if let Some(srq) = &self.srq {
+            srq.unregister(self.qp_num());
+        }
+        self.event_listener.cq.release(self.cq_entries);
+        let errno = unsafe { ibv_destroy_qp(self.as_ptr()) };
+        assert_eq!(errno, 0);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueuePairState {
+    Reset,
+    Init,
+    Rtr,
+    Rts,
+    Sqd,
+    Sqe,
+    Error,
+    Unknown(u32),
+}
+
+impl From<ibv_qp_state::Type> for QueuePairState {
+    fn from(state: ibv_qp_state::Type) -> Self {
+        match state {
+            ibv_qp_state::IBV_QPS_RESET => Self::Reset,
+            ibv_qp_state::IBV_QPS_INIT => Self::Init,
+            ibv_qp_state::IBV_QPS_RTR => Self::Rtr,
+            ibv_qp_state::IBV_QPS_RTS => Self::Rts,
+            ibv_qp_state::IBV_QPS_SQD => Self::Sqd,
+            ibv_qp_state::IBV_QPS_SQE => Self::Sqe,
+            ibv_qp_state::IBV_QPS_ERR => Self::Error,
+            state => Self::Unknown(state),
+        }
+    }
+}
+
+pub mod state {
+    pub struct Reset;
+    pub struct Init;
+    pub struct Rtr;
+    pub struct Rts;
+    pub struct Sqd;
+    pub struct Error;
+}
+
+/// A QP handle that only offers the transitions legal in state `S`
+pub struct TypedQueuePair<S> {
+    qp: Arc<QueuePair>,
+    _state: PhantomData<S>,
+}
+
+impl<S> TypedQueuePair<S> {
+    fn into_state<N>(self) -> TypedQueuePair<N> {
+        TypedQueuePair {
+            qp: self.qp,
+            _state: PhantomData,
+        }
+    }
+
+    /// Applies `attr` from the CM, forcing its state to `target`
+    fn transition<N>(
+        self,
+        target: ibv_qp_state::Type,
+        attr: &mut ibv_qp_attr,
+        mask: i32,
+    ) -> io::Result<TypedQueuePair<N>> {
+        attr.qp_state = target;
+        self.qp.modify(attr, mask)?;
+        Ok(self.into_state())
+    }
+
+    pub fn qp(&self) -> &Arc<QueuePair> {
+        &self.qp
+    }
+
+    pub fn into_inner(self) -> Arc<QueuePair> {
+        self.qp
+    }
+
+    pub fn into_error(self) -> io::Result<TypedQueuePair<state::Error>> {
+        self.qp.modify_to_error()?;
+        Ok(self.into_state())
+    }
+
+    pub fn into_reset(self) -> io::Result<TypedQueuePair<state::Reset>> {
+        TypedQueuePair::reset(self.qp)
+    }
+}
+
+impl TypedQueuePair<state::Reset> {
+    /// Any state may move to RESET, this is also how a fresh QP enters the lifecycle.
+    /// RESET discards outstanding work requests and their completions, so the operations
+    /// waiting for them fail. The QP passes through ERR first to stop the device using
+    /// their buffers.
+    pub fn reset(qp: Arc<QueuePair>) -> io::Result<Self> {
+        // Nothing new gets posted while the QP is on its way to RESET
+        qp.failure
+            .fail(format!("qp {} is being reset", qp.qp_num()));
+        qp.modify_to_error()?;
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        attr.qp_state = ibv_qp_state::IBV_QPS_RESET;
+        qp.modify(&mut attr, ibv_qp_attr_mask::IBV_QP_STATE.0 as _)?;
+        qp.failure.reset();
+        *qp.remote.lock().unwrap() = None;
+        // The unsignaled WRs are gone with the rest
+        qp.uncovered.lock().unwrap().clear();
+        Ok(Self {
+            qp,
+            _state: PhantomData,
+        })
+    }
+
+    pub fn into_init(self, access: ibv_access_flags) -> io::Result<TypedQueuePair<state::Init>> {
+        self.qp.modify_to_init(access)?;
+        Ok(self.into_state())
+    }
+
+    pub fn into_init_with(
+        self,
+        attr: &mut ibv_qp_attr,
+        mask: i32,
+    ) -> io::Result<TypedQueuePair<state::Init>> {
+        self.transition(ibv_qp_state::IBV_QPS_INIT, attr, mask)
+    }
+
+    pub fn into_init_ud(self, qkey: u32) -> io::Result<TypedQueuePair<state::Init>> {
+        self.qp.modify_to_init_ud(qkey)?;
+        Ok(self.into_state())
+    }
+}
+
+impl TypedQueuePair<state::Init> {
+    pub fn into_rtr(
+        self,
+        remote: QueuePairEndpoint,
+        path_mtu: u32,
+        start_psn: u32,
+        max_dest_rd_atomic: u8,
+    ) -> io::Result<TypedQueuePair<state::Rtr>> {
+        self.qp.modify_to_rtr(
+            remote,
+            path_mtu,
+            start_psn,
+            max_dest_rd_atomic,
+            self.qp.config.min_rnr_timer,
+        )?;
+        *self.qp.remote.lock().unwrap() = Some(remote);
+        debug!("rtr");
+        Ok(self.into_state())
+    }
+
+    pub fn into_rtr_with(
+        self,
+        attr: &mut ibv_qp_attr,
+        mask: i32,
+    ) -> io::Result<TypedQueuePair<state::Rtr>> {
+        self.transition(ibv_qp_state::IBV_QPS_RTR, attr, mask)
+    }
+
+    pub fn into_rtr_ud(self) -> io::Result<TypedQueuePair<state::Rtr>> {
+        self.qp.modify_to_rtr_ud()?;
+        Ok(self.into_state())
+    }
+
+    /// Moves to RTS with the parameters agreed on with the peer
+    pub fn handshake(self, negotiated: &Negotiated) -> io::Result<TypedQueuePair<state::Rts>> {
+        self.into_rtr(
+            negotiated.remote,
+            negotiated.path_mtu,
+            negotiated.remote_psn,
+            negotiated.dest_rd_atomic,
+        )?
+        .into_rts(negotiated.local_psn, negotiated.rd_atomic)
+    }
+}
+
+impl TypedQueuePair<state::Rtr> {
+    pub fn into_rts(
+        self,
+        start_psn: u32,
+        max_rd_atomic: u8,
+    ) -> io::Result<TypedQueuePair<state::Rts>> {
+        let config = self.qp.config;
+        self.qp.modify_to_rts(
+            config.timeout,
+            config.retry_cnt,
+            config.rnr_retry,
+            start_psn,
+            max_rd_atomic,
+        )?;
+        debug!("rts");
+        Ok(self.into_state())
+    }
+
+    pub fn into_rts_with(
+        self,
+        attr: &mut ibv_qp_attr,
+        mask: i32,
+    ) -> io::Result<TypedQueuePair<state::Rts>> {
+        self.transition(ibv_qp_state::IBV_QPS_RTS, attr, mask)
+    }
+
+    pub fn into_rts_ud(self, start_psn: u32) -> io::Result<TypedQueuePair<state::Rts>> {
+        self.qp.modify_to_rts_ud(start_psn)?;
+        Ok(self.into_state())
+    }
+}
+
+impl TypedQueuePair<state::Rts> {
+    /// Stops processing new send WRs, outstanding ones still complete
+    pub fn into_sqd(self) -> io::Result<TypedQueuePair<state::Sqd>> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        self.transition(
+            ibv_qp_state::IBV_QPS_SQD,
+            &mut attr,
+            ibv_qp_attr_mask::IBV_QP_STATE.0 as _,
+        )
+    }
+}
+
+impl TypedQueuePair<state::Sqd> {
+    pub fn into_rts(self) -> io::Result<TypedQueuePair<state::Rts>> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_qp_attr>() };
+        self.transition(
+            ibv_qp_state::IBV_QPS_RTS,
+            &mut attr,
+            ibv_qp_attr_mask::IBV_QP_STATE.0 as _,
+        )
+    }
+}
+
+pub trait QueuePairOp {
+    type Output;
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()>;
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output>;
+
+    /// Whether the WR goes to the receive queue, the others take a send queue slot
+    fn is_receive(&self) -> bool {
+        false
+    }
+
+    /// Handles to the MRs the WR uses, held past a timeout until the WR completes
+    fn retain(&mut self) -> Retained;
+}
+
+/// MR handles that keep the memory of an abandoned WR allocated and registered
+pub struct Retained {
+    _local: Vec<LocalMemoryRegion>,
+    _remote: Vec<RemoteMemoryRegion>,
+    owned: Option<(LocalMemoryRegion, oneshot::Sender<LocalMemoryRegion>)>,
+}
+
+impl Retained {
+    fn new(local: &[&LocalMemoryRegion], remote: &[&RemoteMemoryRegion]) -> Self {
+        Self {
+            _local: local.iter().map(|lm| lm.retain()).collect(),
+            _remote: remote.iter().map(|rm| rm.retain()).collect(),
+            owned: None,
+        }
+    }
+
+    /// Leaks the memory, a waiting `Reclaim` learns that the buffer is gone
+    fn leak(mut self) {
+        let owned = self.owned.take().map(|(lm, _sender)| lm);
+        mem::forget((
+            mem::take(&mut self._local),
+            mem::take(&mut self._remote),
+            owned,
+        ));
+    }
+}
+
+impl Drop for Retained {
+    /// Hands the buffer of an owned operation back to its `Reclaim` once the WR is done
+    fn drop(&mut self) {
+        if let Some((lm, sender)) = self.owned.take() {
+            let _ = sender.send(lm);
+        }
+    }
+}
+
+pub struct QPSend<'lm> {
+    lms: Vec<&'lm LocalMemoryRegion>,
+    imm: Option<u32>,
+    len: usize,
+}
+
+impl<'lm> QPSend<'lm> {
+    fn new(lms: Vec<&'lm LocalMemoryRegion>, imm: Option<u32>) -> Self {
+        Self {
+            len: lms.iter().map(|lm| lm.length()).sum(),
+            lms,
+            imm,
+        }
+    }
+}
+
+impl<'lm> QueuePairOp for QPSend<'lm> {
+    type Output = ();
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_send(self.lms.to_owned(), self.imm, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        wc.err()
+            .map(|sz| assert_eq!(sz, self.len))
+            .map_err(Into::into)
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&self.lms, &[])
+    }
+}
+
+pub struct QPInline<'a> {
+    data: &'a [u8],
+    rm: Option<&'a RemoteMemoryRegion>,
+}
+
+impl<'a> QPInline<'a> {
+    fn new(data: &'a [u8], rm: Option<&'a RemoteMemoryRegion>) -> Self {
+        Self { data, rm }
+    }
+}
+
+impl<'a> QueuePairOp for QPInline<'a> {
+    type Output = ();
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_inline(self.data, self.rm, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        wc.err().map(|_| ()).map_err(Into::into)
+    }
+
+    /// The payload was copied into the WR, only the target has to stay
+    fn retain(&mut self) -> Retained {
+        Retained::new(&[], &Vec::from_iter(self.rm))
+    }
+}
+
+pub struct QPRecv<'lm> {
+    lms: Vec<&'lm LocalMemoryRegion>,
+}
+
+impl<'lm> QPRecv<'lm> {
+    fn new(lms: Vec<&'lm LocalMemoryRegion>) -> Self {
+        Self { lms }
+    }
+}
+
+impl<'lm> QueuePairOp for QPRecv<'lm> {
+    type Output = Received;
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_receive(self.lms.to_owned(), wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        Received::from_wc(&wc)
+    }
+
+    fn is_receive(&self) -> bool {
+        true
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&self.lms, &[])
+    }
+}
+
+/// What consumed a posted receive
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Received {
+    /// `len` bytes landed in the receive buffer
+    Send { len: usize, imm: Option<u32> },
+    /// `len` bytes landed in a local MR written by the peer, the receive buffer is untouched
+    WriteWithImm { len: usize, imm: u32 },
+}
+
+impl Received {
+    pub(crate) fn from_wc(wc: &WorkCompletion) -> io::Result<Self> {
+        let len = wc.err()?;
+        Ok(match wc.imm_data() {
+            Some(imm) if wc.is_recv_rdma_with_imm() => Self::WriteWithImm { len, imm },
+            imm => Self::Send { len, imm },
+        })
+    }
+}
+
+pub struct QPSendTo<'a> {
+    lms: Vec<&'a LocalMemoryRegion>,
+    ah: &'a AddressHandle,
+}
+
+impl<'a> QPSendTo<'a> {
+    fn new(lms: Vec<&'a LocalMemoryRegion>, ah: &'a AddressHandle) -> Self {
+        Self { lms, ah }
+    }
+}
+
+impl<'a> QueuePairOp for QPSendTo<'a> {
+    type Output = ();
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_send_to(self.lms.to_owned(), self.ah, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        wc.err().map(|_| ()).map_err(Into::into)
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&self.lms, &[])
+    }
+}
+
+pub struct QPRecvFrom<'lm> {
+    lm: &'lm LocalMemoryRegion,
+}
+
+impl<'lm> QPRecvFrom<'lm> {
+    fn new(lm: &'lm LocalMemoryRegion) -> Self {
+        Self { lm }
+    }
+}
+
+impl<'lm> QueuePairOp for QPRecvFrom<'lm> {
+    /// The payload length and the sender
+    type Output = (usize, DatagramSource);
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.expect_type(QueuePairType::Ud)?;
+        if self.lm.length() < GRH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "datagram buffer needs at least {} bytes for the GRH",
+                    GRH_LEN
+                ),
+            ));
+        }
+        qp.submit_receive(vec![self.lm], wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        let len = wc.err()?;
+        let grh = wc
+            .has_grh()
+            .then(|| Grh::parse(&self.lm.as_slice()[..GRH_LEN]));
+        // Only a GRH that arrived counts towards the received bytes
+        let len = match grh {
+            Some(_) => len.saturating_sub(GRH_LEN),
+            None => len,
+        };
+        let source = DatagramSource {
+            qp_num: wc.src_qp(),
+            grh,
+        };
+        Ok((len, source))
+    }
+
+    fn is_receive(&self) -> bool {
+        true
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&[self.lm], &[])
+    }
+}
+
+pub struct QPRead<'a> {
+    lms: Vec<&'a LocalMemoryRegion>,
+    rm: &'a RemoteMemoryRegion,
+    len: usize,
+}
+
+impl<'a> QPRead<'a> {
+    fn new(lms: Vec<&'a LocalMemoryRegion>, rm: &'a RemoteMemoryRegion) -> Self {
+        Self {
+            len: lms.iter().map(|lm| lm.length()).sum(),
+            lms,
+            rm,
+        }
+    }
+}
+
+impl<'a> QueuePairOp for QPRead<'a> {
+    type Output = ();
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_read(self.lms.to_owned(), self.rm, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        wc.err()
+            .map(|sz| assert_eq!(sz, self.len))
+            .map_err(Into::into)
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&self.lms, &[self.rm])
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum AtomicOp {
+    CompareAndSwap { expected: u64, new: u64 },
+    FetchAndAdd { delta: u64 },
+}
+
+/// Atomics work on exactly 8 naturally aligned bytes
+fn check_atomic_target(lm: &LocalMemoryRegion, rm: &RemoteMemoryRegion) -> io::Result<()> {
+    if rm.length() != 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("atomic target must be 8 bytes, got {}", rm.length()),
+        ));
+    }
+    if rm.as_ptr() as usize % 8 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("atomic target {:p} is not 8 byte aligned", rm.as_ptr()),
+        ));
+    }
+    if lm.length() != 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("atomic result buffer must be 8 bytes, got {}", lm.length()),
+        ));
+    }
+    Ok(())
+}
+
+pub struct QPAtomic<'a> {
+    lm: &'a LocalMemoryRegion,
+    rm: &'a RemoteMemoryRegion,
+    op: AtomicOp,
+}
+
+impl<'a> QPAtomic<'a> {
+    fn new(lm: &'a LocalMemoryRegion, rm: &'a RemoteMemoryRegion, op: AtomicOp) -> Self {
+        Self { lm, rm, op }
+    }
+}
+
+impl<'a> QueuePairOp for QPAtomic<'a> {
+    /// The value at the target before the operation
+    type Output = u64;
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_atomic(self.lm, self.rm, self.op, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        wc.err()?;
+        Ok(u64::from_ne_bytes(self.lm.as_slice().try_into().unwrap()))
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&[self.lm], &[self.rm])
+    }
+}
+
+pub struct QPWrite<'a> {
+    lms: Vec<&'a LocalMemoryRegion>,
+    rm: &'a RemoteMemoryRegion,
+    imm: Option<u32>,
+    len: usize,
+}
+
+impl<'a> QPWrite<'a> {
+    fn new(lms: Vec<&'a LocalMemoryRegion>, rm: &'a RemoteMemoryRegion, imm: Option<u32>) -> Self {
+        Self {
+            len: lms.iter().map(|lm| lm.length()).sum(),
+            lms,
+            rm,
+            imm,
+        }
+    }
+}
+
+impl<'a> QueuePairOp for QPWrite<'a> {
+    type Output = ();
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        qp.submit_write(self.lms.to_owned(), self.rm, self.imm, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        wc.err()
+            .map(|sz| assert_eq!(sz, self.len))
+            .map_err(Into::into)
+    }
+
+    fn retain(&mut self) -> Retained {
+        Retained::new(&self.lms, &[self.rm])
+    }
+}
+
+/// The WR of a `QPOwned`, it keeps whatever else the WR needs besides the local buffer
+pub trait OwnedKind: Unpin {
+    type Output;
+
+    fn submit(
+        &self,
+        qp: &QueuePair,
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()>;
+
+    fn parse_wc(&self, wc: WorkCompletion, len: usize) -> io::Result<Self::Output>;
+
+    fn is_receive(&self) -> bool {
+        false
+    }
+
+    fn retain(&self) -> Vec<RemoteMemoryRegion> {
+        Vec::new()
+    }
+}
+
+pub struct OwnedSend;
+
+impl OwnedKind for OwnedSend {
+    type Output = ();
+
+    fn submit(
+        &self,
+        qp: &QueuePair,
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        qp.submit_send(vec![lm], None, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion, len: usize) -> io::Result<Self::Output> {
+        wc.err().map(|sz| assert_eq!(sz, len)).map_err(Into::into)
+    }
+}
+
+pub struct OwnedRecv;
+
+impl OwnedKind for OwnedRecv {
+    type Output = Received;
+
+    fn submit(
+        &self,
+        qp: &QueuePair,
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        qp.submit_receive(vec![lm], wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion, _len: usize) -> io::Result<Self::Output> {
+        Received::from_wc(&wc)
+    }
+
+    fn is_receive(&self) -> bool {
+        true
+    }
+}
+
+pub struct OwnedRead {
+    rm: RemoteMemoryRegion,
+}
+
+impl OwnedKind for OwnedRead {
+    type Output = ();
+
+    fn submit(
+        &self,
+        qp: &QueuePair,
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        qp.submit_read(vec![lm], &self.rm, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion, len: usize) -> io::Result<Self::Output> {
+        wc.err().map(|sz| assert_eq!(sz, len)).map_err(Into::into)
+    }
+
+    fn retain(&self) -> Vec<RemoteMemoryRegion> {
+        vec![self.rm.retain()]
+    }
+}
+
+pub struct OwnedWrite {
+    rm: RemoteMemoryRegion,
+}
+
+impl OwnedKind for OwnedWrite {
+    type Output = ();
+
+    fn submit(
+        &self,
+        qp: &QueuePair,
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+    ) -> io::Result<()> {
+        qp.submit_write(vec![lm], &self.rm, None, wr_id)
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion, len: usize) -> io::Result<Self::Output> {
+        wc.err().map(|sz| assert_eq!(sz, len)).map_err(Into::into)
+    }
+
+    fn retain(&self) -> Vec<RemoteMemoryRegion> {
+        vec![self.rm.retain()]
+    }
+}
+
+/// An operation that owns its local buffer. An abandoned WR takes the buffer along and hands
+/// it back through `Reclaim` once the device is done with it
+pub struct QPOwned<K: OwnedKind> {
+    lm: Option<LocalMemoryRegion>,
+    len: usize,
+    reclaim: Option<oneshot::Receiver<LocalMemoryRegion>>,
+    kind: K,
+}
+
+impl<K: OwnedKind> QPOwned<K> {
+    fn new(lm: LocalMemoryRegion, kind: K) -> Self {
+        Self {
+            len: lm.length(),
+            lm: Some(lm),
+            reclaim: None,
+            kind,
+        }
+    }
+
+    fn reclaim(&mut self) -> Reclaim {
+        match (self.lm.take(), self.reclaim.take()) {
+            (Some(lm), _) => Reclaim::ready(lm),
+            (None, Some(receiver)) => Reclaim(ReclaimState::InFlight(receiver)),
+            (None, None) => panic!("buffer of an owned operation reclaimed twice"),
+        }
+    }
+}
+
+impl<K: OwnedKind> QueuePairOp for QPOwned<K> {
+    type Output = K::Output;
+
+    fn submit(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<()> {
+        match &self.lm {
+            Some(lm) => self.kind.submit(qp, lm, wr_id),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer of the owned operation is gone",
+            )),
+        }
+    }
+
+    fn parse_wc(&self, wc: WorkCompletion) -> io::Result<Self::Output> {
+        self.kind.parse_wc(wc, self.len)
+    }
+
+    fn is_receive(&self) -> bool {
+        self.kind.is_receive()
+    }
+
+    /// Moves the buffer into the abandoned WR, `reclaim` gets it back after the completion
+    fn retain(&mut self) -> Retained {
+        let (sender, receiver) = oneshot::channel();
+        self.reclaim = Some(receiver);
+        Retained {
+            _local: Vec::new(),
+            _remote: self.kind.retain(),
+            owned: self.lm.take().map(|lm| (lm, sender)),
+        }
+    }
+}
+
+/// The buffer of an owned operation, resolves once the device no longer uses it. It is ready
+/// right away unless the operation gave up while its WR was in flight
+pub struct Reclaim(ReclaimState);
+
+enum ReclaimState {
+    Ready(Option<LocalMemoryRegion>),
+    InFlight(oneshot::Receiver<LocalMemoryRegion>),
+}
+
+impl Reclaim {
+    pub(crate) fn ready(lm: LocalMemoryRegion) -> Self {
+        Self(ReclaimState::Ready(Some(lm)))
+    }
+
+    /// Whether the buffer is back without waiting
+    pub fn is_ready(&self) -> bool {
+        matches!(self.0, ReclaimState::Ready(_))
+    }
+}
+
+impl Future for Reclaim {
+    /// Fails if the buffer was leaked because no runtime could wait for its WR
+    type Output = io::Result<LocalMemoryRegion>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        match &mut self.get_mut().0 {
+            ReclaimState::Ready(lm) => {
+                Poll::Ready(Ok(lm.take().expect("Reclaim polled after completion")))
+            }
+            ReclaimState::InFlight(receiver) => {
+                Poll::Ready(ready!(Pin::new(receiver).poll(cx)).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "buffer of the abandoned operation was leaked",
+                    )
+                }))
+            }
+        }
+    }
+}
+
+/// An owned operation, resolves to its result and the buffer it took
+pub struct OwnedOps<K: OwnedKind> {
+    ops: QueuePairOps<QPOwned<K>>,
+}
+
+impl<K: OwnedKind> OwnedOps<K> {
+    fn new(qp: Arc<QueuePair>, lm: LocalMemoryRegion, kind: K) -> Self {
+        Self {
+            ops: QueuePairOps::new(qp, QPOwned::new(lm, kind)),
+        }
+    }
+
+    /// Fails with `OperationTimeout` once `deadline` passes, the buffer then comes back after
+    /// the completion of the WR
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        Self {
+            ops: self.ops.with_deadline(deadline),
+        }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+}
+
+impl<K: OwnedKind> Future for OwnedOps<K> {
+    type Output = BufResult<K::Output, Reclaim>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let s = self.get_mut();
+        let ans = ready!(Pin::new(&mut s.ops).poll(cx));
+        Poll::Ready((ans, s.ops.op.reclaim()))
+    }
+}
+
+enum BatchOp<'a> {
+    Send(&'a LocalMemoryRegion),
+    Read(&'a LocalMemoryRegion, &'a RemoteMemoryRegion),
+    Write(&'a LocalMemoryRegion, &'a RemoteMemoryRegion),
+}
+
+impl<'a> BatchOp<'a> {
+    fn build(&self, qp: &QueuePair, wr_id: WorkRequestId) -> io::Result<SendWr> {
+        match *self {
+            BatchOp::Send(lm) => Ok(qp.send_wr(vec![lm], None, wr_id)),
+            BatchOp::Read(lm, rm) => qp.read_wr(vec![lm], rm, wr_id),
+            BatchOp::Write(lm, rm) => qp.write_wr(vec![lm], rm, None, wr_id),
+        }
+    }
+
+    fn retain(&self) -> Retained {
+        match *self {
+            BatchOp::Send(lm) => Retained::new(&[lm], &[]),
+            BatchOp::Read(lm, rm) | BatchOp::Write(lm, rm) => Retained::new(&[lm], &[rm]),
+        }
+    }
+}
+
+/// Send queue WRs that reach the device with one doorbell
+pub struct SendBatch<'a> {
+    qp: Arc<QueuePair>,
+    ops: Vec<BatchOp<'a>>,
+    /// Dropped once the batch completed, e.g. to keep `Rdma::close` waiting for it
+    _guard: Option<Box<dyn Send + Sync + 'a>>,
+}
+
+impl<'a> SendBatch<'a> {
+    pub(crate) fn with_guard(mut self, guard: impl Send + Sync + 'a) -> Self {
+        self._guard = Some(Box::new(guard));
+        self
+    }
+
+    pub fn send(mut self, lm: &'a LocalMemoryRegion) -> Self {
+        self.ops.push(BatchOp::Send(lm));
+        self
+    }
+
+    pub fn read(mut self, lm: &'a mut LocalMemoryRegion, rm: &'a RemoteMemoryRegion) -> Self {
+        self.ops.push(BatchOp::Read(lm, rm));
+        self
+    }
+
+    pub fn write(mut self, lm: &'a LocalMemoryRegion, rm: &'a RemoteMemoryRegion) -> Self {
+        self.ops.push(BatchOp::Write(lm, rm));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Resolves once every WR completed, with the first error if any of them failed
+    pub async fn post(self) -> io::Result<()> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+        if self.ops.len() > self.qp.cap.max_send_wr as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "batch of {} wrs exceeds max_send_wr {} of qp {}",
+                    self.ops.len(),
+                    self.qp.cap.max_send_wr,
+                    self.qp.qp_num()
+                ),
+            ));
+        }
+        let generation = self.qp.failure.check()?;
+        let n = self.ops.len();
+        let mut slots = self
+            .qp
+            .send_slots
+            .clone()
+            .acquire_many_owned(n as u32)
+            .await
+            .unwrap();
+        let mut pending = Vec::with_capacity(n);
+        let mut ans = Ok(());
+        // The WRs hold raw pointers, they must be gone before the first await
+        {
+            let mut srs = Vec::with_capacity(n);
+            for op in &self.ops {
+                let (wr_id, recv) = self.qp.event_listener.register();
+                pending.push((wr_id, recv));
+                match op.build(&self.qp, wr_id) {
+                    Ok(sr) => srs.push(sr),
+                    Err(err) => {
+                        for (wr_id, _) in pending {
+                            self.qp.event_listener.unregister(wr_id);
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            if let Err((posted, err)) = self.qp.post_send_batch(&mut srs) {
+                // The WRs in front of the rejected one are on the device and still use the buffers
+                for (wr_id, _) in pending.drain(posted..) {
+                    self.qp.event_listener.unregister(wr_id);
+                }
+                let mut unposted = slots.split(n - posted).unwrap();
+                self.qp.signal_uncovered(unposted.split(1).unwrap());
+                ans = Err(err);
+            }
+        }
+        let mut posted = PostedBatch {
+            qp: self.qp.clone(),
+            generation,
+            wrs: VecDeque::with_capacity(pending.len()),
+        };
+        for ((wr_id, recv), op) in pending.into_iter().zip(&self.ops) {
+            posted
+                .wrs
+                .push_back((wr_id, recv, slots.split(1).unwrap(), op.retain()));
+        }
+        while let Some((wr_id, recv, ..)) = posted.wrs.front_mut() {
+            let completed = self.qp.wait_completion(*wr_id, generation, recv).await;
+            // Failed without a flush, dropping `posted` abandons the WRs still on the device
+            if completed.is_err() && self.qp.failure.generation() == generation {
+                return ans.and(completed.map(|_| ()));
+            }
+            ans = ans.and(completed.and_then(|wc| wc.err().map(|_| ()).map_err(Into::into)));
+            let _ = posted.wrs.pop_front();
+        }
+        ans
+    }
+}
+
+/// WRs of a batch not waited for yet, abandoned to the QP if `post` is dropped
+struct PostedBatch {
+    qp: Arc<QueuePair>,
+    generation: u64,
+    wrs: VecDeque<(
+        WorkRequestId,
+        mpsc::Receiver<WorkCompletion>,
+        OwnedSemaphorePermit,
+        Retained,
+    )>,
+}
+
+impl Drop for PostedBatch {
+    fn drop(&mut self) {
+        for (wr_id, recv, slot, retained) in self.wrs.drain(..) {
+            self.qp
+                .track_abandoned(wr_id, self.generation, recv, slot, retained);
+        }
+    }
+}
+
+/// An operation or agent request that missed its deadline
+#[derive(Error, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperationTimeout {
+    #[error("deadline passed before the work request was posted")]
+    NotPosted,
+    /// The buffers stay in use until the WR completes
+    #[error("deadline passed while the work request was in flight")]
+    InFlight,
+    #[error("agent request timed out after {0:?}")]
+    AgentRequest(Duration),
+}
+
+impl From<OperationTimeout> for io::Error {
+    fn from(e: OperationTimeout) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+enum QueuePairOpsState {
+    Init,
+    /// The WR, the generation it was posted in, its completion and its send or receive slot
+    Submitted(
+        WorkRequestId,
+        u64,
+        mpsc::Receiver<WorkCompletion>,
+        OwnedSemaphorePermit,
+    ),
+    Done,
+}
+
+/// An operation on borrowed buffers. It is not cancellation safe: once dropped or timed out
+/// with its WR in flight the caller has the buffers back while the device may still use them.
+/// The memory stays allocated, but use the `*_owned` operations under `select!` and timeouts
+pub struct QueuePairOps<Op: QueuePairOp + Unpin> {
     qp: Arc<QueuePair>,
     state: QueuePairOpsState,
     op: Op,
+    /// Slots for the WR, or for the empty write signaling it
+    slot_acquire: SlotAcquire,
+    signal_armed: bool,
+    deadline: Option<Pin<Box<Sleep>>>,
 }
 
 impl<Op: QueuePairOp + Unpin> QueuePairOps<Op> {
@@ -423,26 +2394,133 @@ impl<Op: QueuePairOp + Unpin> QueuePairOps<Op> {
             qp,
             state: QueuePairOpsState::Init,
             op,
+            slot_acquire: SlotAcquire::default(),
+            signal_armed: false,
+            deadline: None,
+        }
+    }
+
+    /// Fails with `OperationTimeout` once `deadline` passes
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        self
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Pending until the deadline passes, a posted WR is then handed over to the QP
+    fn poll_deadline(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<Op::Output>> {
+        match &mut self.deadline {
+            Some(deadline) => ready!(deadline.as_mut().poll(cx)),
+            None => return Poll::Pending,
+        }
+        let err = if self.abandon() {
+            OperationTimeout::InFlight
+        } else {
+            OperationTimeout::NotPosted
+        };
+        Poll::Ready(Err(err.into()))
+    }
+
+    /// Hands a posted WR and its buffers over to the QP, returns whether there was one
+    fn abandon(&mut self) -> bool {
+        match mem::replace(&mut self.state, QueuePairOpsState::Done) {
+            QueuePairOpsState::Submitted(wr_id, generation, recv, slot) => {
+                self.settle();
+                self.qp
+                    .track_abandoned(wr_id, generation, recv, slot, self.op.retain());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Called when an operation stops waiting for its posted WR
+    fn settle(&self) {
+        if !self.op.is_receive() {
+            let _ = self.qp.awaited.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// An unsignaled WR with no signaled one behind it would wait forever. The first poll
+    /// leaves concurrent operations a chance to post one, the second posts an empty write
+    fn signal_if_uncovered(&mut self, wr_id: WorkRequestId, cx: &mut std::task::Context<'_>) {
+        if !self.qp.is_uncovered(wr_id) {
+            return;
+        }
+        if self.signal_armed {
+            // Without a free slot this waits for one like any other operation
+            if let Poll::Ready(slot) = self.slot_acquire.poll_acquire(&self.qp.send_slots, 1, cx) {
+                self.qp.signal_uncovered(slot);
+            }
+        } else {
+            self.signal_armed = true;
+            cx.waker().wake_by_ref();
         }
     }
 }
 
 impl<Op: QueuePairOp + Unpin> Future for QueuePairOps<Op> {
-    type Output = Op::Output;
+    type Output = io::Result<Op::Output>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let s = self.get_mut();
         match &mut s.state {
             QueuePairOpsState::Init => {
+                let generation = s.qp.failure.check()?;
+                let slots = s.qp.slots(s.op.is_receive());
+                let slot = match s.slot_acquire.poll_acquire(slots, 1, cx) {
+                    Poll::Ready(slot) => slot,
+                    Poll::Pending => return s.poll_deadline(cx),
+                };
                 let (wr_id, recv) = s.qp.event_listener.register();
-                s.op.submit(&s.qp, wr_id).unwrap();
-                s.state = QueuePairOpsState::Submitted(recv);
+                if let Err(err) = s.op.submit(&s.qp, wr_id) {
+                    s.qp.event_listener.unregister(wr_id);
+                    return Poll::Ready(Err(err));
+                }
+                if !s.op.is_receive() {
+                    let _ = s.qp.awaited.fetch_add(1, Ordering::AcqRel);
+                }
+                s.state = QueuePairOpsState::Submitted(wr_id, generation, recv, slot);
                 Pin::new(s).poll(cx)
             }
-            QueuePairOpsState::Submitted(recv) => {
-                let wc = ready!(recv.poll_recv(cx)).unwrap();
-                Poll::Ready(s.op.parse_wc(wc))
+            QueuePairOpsState::Submitted(wr_id, generation, recv, _) => {
+                let (wr_id, generation) = (*wr_id, *generation);
+                let ans = match recv.poll_recv(cx) {
+                    Poll::Ready(Some(wc)) => s.op.parse_wc(wc),
+                    Poll::Ready(None) => Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "completion queue poller stopped",
+                    )),
+                    Poll::Pending => match s.qp.failure.poll(wr_id, generation, cx) {
+                        // Failed without a flush, unlike after a reset the WR may still run
+                        Poll::Ready(err) if s.qp.failure.generation() == generation => {
+                            let _ = s.abandon();
+                            return Poll::Ready(Err(err));
+                        }
+                        Poll::Ready(err) => Err(err),
+                        Poll::Pending => {
+                            s.signal_if_uncovered(wr_id, cx);
+                            return s.poll_deadline(cx);
+                        }
+                    },
+                };
+                s.qp.failure.unwatch(wr_id);
+                s.settle();
+                s.state = QueuePairOpsState::Done;
+                Poll::Ready(ans)
             }
+            QueuePairOpsState::Done => panic!("QueuePairOps polled after completion"),
         }
     }
 }
+
+impl<Op: QueuePairOp + Unpin> Drop for QueuePairOps<Op> {
+    /// The device may still use the buffers of a posted WR, e.g. when a `select!` drops the
+    /// operation, so they are kept alive until its completion arrives
+    fn drop(&mut self) {
+        let _ = self.abandon();
+    }
+}
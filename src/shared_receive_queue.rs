@@ -0,0 +1,416 @@
+use crate::{
+    async_event::AsyncEvent,
+    completion_queue::{WorkCompletion, WorkRequestId},
+    device::DeviceAttr,
+    event_listener::EventListener,
+    memory_region::LocalMemoryRegion,
+    mr_allocator::MRAllocator,
+    protection_domain::ProtectionDomain,
+    queue_pair::Received,
+    work_request::RecvWr,
+};
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use rdma_sys::{
+    ibv_create_srq, ibv_destroy_srq, ibv_modify_srq, ibv_post_srq_recv, ibv_recv_wr, ibv_srq,
+    ibv_srq_attr, ibv_srq_attr_mask, ibv_srq_init_attr,
+};
+use std::{
+    alloc::Layout,
+    collections::{HashMap, VecDeque},
+    io,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError, OwnedPermit},
+        Notify,
+    },
+    task::JoinHandle,
+};
+use tracing::debug;
+
+/// A receive buffer and what consumed it
+pub(crate) type SrqMessage = io::Result<(LocalMemoryRegion, Received)>;
+type PostedReceive = BoxFuture<'static, (Option<WorkCompletion>, LocalMemoryRegion)>;
+type PendingPermit = BoxFuture<
+    'static,
+    (
+        u32,
+        mpsc::Sender<SrqMessage>,
+        Option<OwnedPermit<SrqMessage>>,
+    ),
+>;
+
+const SRQ_CHANNEL_SIZE: usize = 1024;
+const SRQ_REFILL_RETRY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SrqConfig {
+    pub max_wr: u32,
+    pub max_sge: u32,
+    /// The limit event fires once fewer receives than this are posted, 0 disables it
+    pub limit: u32,
+}
+
+impl Default for SrqConfig {
+    fn default() -> Self {
+        Self {
+            max_wr: 128,
+            max_sge: 1,
+            limit: 16,
+        }
+    }
+}
+
+impl SrqConfig {
+    pub fn validate(&self, attr: &DeviceAttr) -> io::Result<()> {
+        let limits = [
+            ("max_wr", self.max_wr, 1, attr.max_srq_wr),
+            ("max_sge", self.max_sge, 1, attr.max_srq_sge),
+            ("limit", self.limit, 0, self.max_wr.saturating_sub(1)),
+        ];
+        for (name, value, min, max) in limits {
+            if value < min || value > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "srq {} is {}, the supported range is {}..={}",
+                        name, value, min, max
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Messages of QPs whose receiver is full, each QP waits for room on its own
+#[derive(Default)]
+struct Overflow {
+    queued: HashMap<u32, VecDeque<SrqMessage>>,
+    len: usize,
+    permits: FuturesUnordered<PendingPermit>,
+}
+
+impl Overflow {
+    fn push(&mut self, qp_num: u32, message: SrqMessage) {
+        self.queued.entry(qp_num).or_default().push_back(message);
+        self.len += 1;
+    }
+
+    fn wait_for_room(&mut self, qp_num: u32, sender: mpsc::Sender<SrqMessage>) {
+        self.permits.push(Box::pin(async move {
+            let permit = sender.clone().reserve_owned().await.ok();
+            (qp_num, sender, permit)
+        }));
+    }
+}
+
+/// Receive buffers shared by all QPs attached to it, receives are routed back by QP number
+pub struct SharedReceiveQueue {
+    pd: Arc<ProtectionDomain>,
+    event_listener: Arc<EventListener>,
+    inner_srq: NonNull<ibv_srq>,
+    config: SrqConfig,
+    receivers: Mutex<HashMap<u32, mpsc::Sender<SrqMessage>>>,
+    /// Channels of registered QPs nobody subscribed to yet, they buffer the early messages
+    parked: Mutex<HashMap<u32, mpsc::Receiver<SrqMessage>>>,
+    limit_reached: Arc<Notify>,
+    limit_events: AtomicUsize,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl SharedReceiveQueue {
+    pub(crate) fn as_ptr(&self) -> *mut ibv_srq {
+        self.inner_srq.as_ptr()
+    }
+
+    pub(crate) fn create(
+        pd: &Arc<ProtectionDomain>,
+        event_listener: Arc<EventListener>,
+        config: SrqConfig,
+    ) -> io::Result<Self> {
+        let attr = DeviceAttr::query(pd.ctx.as_ptr())?;
+        config.validate(&attr)?;
+        event_listener.cq.reserve(config.max_wr, attr.max_cqe)?;
+        let mut init_attr = unsafe { std::mem::zeroed::<ibv_srq_init_attr>() };
+        init_attr.attr.max_wr = config.max_wr;
+        init_attr.attr.max_sge = config.max_sge;
+        let inner_srq = NonNull::new(unsafe { ibv_create_srq(pd.as_ptr(), &mut init_attr) })
+            .ok_or_else(|| {
+                let err = io::Error::last_os_error();
+                event_listener.cq.release(config.max_wr);
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to create srq with {:?}: {}", config, err),
+                )
+            })?;
+        Ok(Self {
+            pd: pd.clone(),
+            event_listener,
+            inner_srq,
+            config,
+            receivers: Mutex::new(HashMap::new()),
+            parked: Mutex::new(HashMap::new()),
+            limit_reached: Arc::new(Notify::new()),
+            limit_events: AtomicUsize::new(0),
+            tasks: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn handle(&self) -> u32 {
+        unsafe { (*self.as_ptr()).handle }
+    }
+
+    pub fn config(&self) -> SrqConfig {
+        self.config
+    }
+
+    pub(crate) fn event_listener(&self) -> &Arc<EventListener> {
+        &self.event_listener
+    }
+
+    /// Arms the limit event, the device reports it only once per arming
+    pub fn arm_limit(&self, limit: u32) -> io::Result<()> {
+        let mut attr = unsafe { std::mem::zeroed::<ibv_srq_attr>() };
+        attr.srq_limit = limit;
+        let mask = ibv_srq_attr_mask::IBV_SRQ_LIMIT;
+        let errno = unsafe { ibv_modify_srq(self.as_ptr(), &mut attr, mask.0 as _) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        Ok(())
+    }
+
+    /// How many times the limit event fired
+    pub fn limit_events(&self) -> usize {
+        self.limit_events.load(Ordering::Acquire)
+    }
+
+    pub async fn limit_reached(&self) {
+        self.limit_reached.notified().await
+    }
+
+    /// Routes messages of the QP `qp_num` to a channel before the QP can receive any, so the
+    /// peer's first messages wait there for `subscribe`
+    pub(crate) fn register(&self, qp_num: u32) {
+        let (sender, receiver) = mpsc::channel(SRQ_CHANNEL_SIZE);
+        self.receivers.lock().unwrap().insert(qp_num, sender);
+        self.parked.lock().unwrap().insert(qp_num, receiver);
+    }
+
+    pub(crate) fn unregister(&self, qp_num: u32) {
+        self.receivers.lock().unwrap().remove(&qp_num);
+        self.parked.lock().unwrap().remove(&qp_num);
+    }
+
+    /// Messages received by the QP `qp_num`, the channel set up by `register` if there is one
+    pub(crate) fn subscribe(&self, qp_num: u32) -> mpsc::Receiver<SrqMessage> {
+        if let Some(receiver) = self.parked.lock().unwrap().remove(&qp_num) {
+            return receiver;
+        }
+        let (sender, receiver) = mpsc::channel(SRQ_CHANNEL_SIZE);
+        self.receivers.lock().unwrap().insert(qp_num, sender);
+        receiver
+    }
+
+    /// Keeps `max_wr` buffers of `layout` posted and arms the limit event
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        allocator: Arc<MRAllocator>,
+        layout: Layout,
+    ) -> io::Result<()> {
+        if self.config.limit > 0 {
+            self.arm_limit(self.config.limit)?;
+        }
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.push(self.replenish(allocator, layout));
+        tasks.push(self.watch_limit());
+        Ok(())
+    }
+
+    fn post_receive(&self, lm: &LocalMemoryRegion, wr_id: WorkRequestId) -> io::Result<()> {
+        let mut rr = RecvWr::new_recv(vec![lm], wr_id);
+        let mut bad_wr = std::ptr::null_mut::<ibv_recv_wr>();
+        self.event_listener.cq.req_notify(false)?;
+        let errno = unsafe { ibv_post_srq_recv(self.as_ptr(), rr.as_mut(), &mut bad_wr) };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        Ok(())
+    }
+
+    /// A full receiver gets its messages queued while the other QPs keep receiving. The queued
+    /// buffers count against `max_wr`, so a receiver that never drains stalls the whole SRQ and
+    /// the peers get RNR NAKs instead of silently lost messages
+    fn dispatch(&self, wc: WorkCompletion, buf: LocalMemoryRegion, overflow: &mut Overflow) {
+        let qp_num = wc.qp_num();
+        let message = Received::from_wc(&wc).map(|received| (buf, received));
+        if overflow.queued.contains_key(&qp_num) {
+            overflow.push(qp_num, message);
+            return;
+        }
+        let sender = match self.receivers.lock().unwrap().get(&qp_num) {
+            Some(sender) => sender.clone(),
+            None => {
+                debug!("dropping srq message of qp {}, nobody receives it", qp_num);
+                return;
+            }
+        };
+        match sender.try_send(message) {
+            Ok(()) => (),
+            Err(TrySendError::Full(message)) => {
+                overflow.push(qp_num, message);
+                overflow.wait_for_room(qp_num, sender);
+            }
+            Err(TrySendError::Closed(_)) => self.remove_receiver(qp_num, &sender),
+        }
+    }
+
+    /// Hands the oldest queued message of `qp_num` to the room its receiver made
+    fn deliver(
+        &self,
+        overflow: &mut Overflow,
+        qp_num: u32,
+        sender: mpsc::Sender<SrqMessage>,
+        permit: Option<OwnedPermit<SrqMessage>>,
+    ) {
+        let mut queue = match overflow.queued.remove(&qp_num) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let permit = match permit {
+            Some(permit) => permit,
+            None => {
+                overflow.len -= queue.len();
+                self.remove_receiver(qp_num, &sender);
+                return;
+            }
+        };
+        if let Some(message) = queue.pop_front() {
+            overflow.len -= 1;
+            permit.send(message);
+        }
+        if !queue.is_empty() {
+            overflow.queued.insert(qp_num, queue);
+            overflow.wait_for_room(qp_num, sender);
+        }
+    }
+
+    fn remove_receiver(&self, qp_num: u32, sender: &mpsc::Sender<SrqMessage>) {
+        let mut receivers = self.receivers.lock().unwrap();
+        if receivers
+            .get(&qp_num)
+            .map_or(false, |s| s.same_channel(sender))
+        {
+            let _ = receivers.remove(&qp_num);
+        }
+    }
+
+    /// Holds only a weak reference, the posted buffers live in the task until the SRQ is gone
+    fn replenish(self: &Arc<Self>, allocator: Arc<MRAllocator>, layout: Layout) -> JoinHandle<()> {
+        let srq = Arc::downgrade(self);
+        let limit_reached = self.limit_reached.clone();
+        tokio::spawn(async move {
+            let mut posted = FuturesUnordered::new();
+            let mut overflow = Overflow::default();
+            loop {
+                match srq.upgrade() {
+                    Some(srq) => srq.refill(&allocator, layout, &mut posted, overflow.len),
+                    None => return,
+                }
+                tokio::select! {
+                    Some((wc, buf)) = posted.next(), if !posted.is_empty() => {
+                        let srq = match srq.upgrade() {
+                            Some(srq) => srq,
+                            None => return,
+                        };
+                        match wc {
+                            Some(wc) => srq.dispatch(wc, buf, &mut overflow),
+                            None => return,
+                        }
+                    }
+                    Some((qp_num, sender, permit)) = overflow.permits.next(),
+                        if !overflow.permits.is_empty() =>
+                    {
+                        match srq.upgrade() {
+                            Some(srq) => srq.deliver(&mut overflow, qp_num, sender, permit),
+                            None => return,
+                        }
+                    }
+                    _ = limit_reached.notified() => debug!("srq limit reached, refilling"),
+                    _ = tokio::time::sleep(SRQ_REFILL_RETRY), if posted.is_empty() => (),
+                }
+            }
+        })
+    }
+
+    /// Keeps the buffers posted and the ones queued for a full receiver at `max_wr`
+    fn refill(
+        &self,
+        allocator: &MRAllocator,
+        layout: Layout,
+        posted: &mut FuturesUnordered<PostedReceive>,
+        queued: usize,
+    ) {
+        while posted.len() + queued < self.config.max_wr as usize {
+            let buf = match allocator.alloc(layout) {
+                Ok(buf) => buf,
+                Err(err) => {
+                    debug!("failed to allocate srq buffer: {}", err);
+                    return;
+                }
+            };
+            let (wr_id, mut receiver) = self.event_listener.register();
+            if let Err(err) = self.post_receive(&buf, wr_id) {
+                self.event_listener.unregister(wr_id);
+                debug!("failed to post srq receive: {}", err);
+                return;
+            }
+            posted.push(Box::pin(async move { (receiver.recv().await, buf) }));
+        }
+    }
+
+    fn watch_limit(self: &Arc<Self>) -> JoinHandle<()> {
+        let srq = Arc::downgrade(self);
+        let handle = self.handle();
+        let mut events = self.pd.ctx.async_events();
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                if event != (AsyncEvent::SrqLimitReached { srq_handle: handle }) {
+                    continue;
+                }
+                let srq = match Weak::upgrade(&srq) {
+                    Some(srq) => srq,
+                    None => return,
+                };
+                srq.limit_events.fetch_add(1, Ordering::AcqRel);
+                srq.limit_reached.notify_waiters();
+                if let Err(err) = srq.arm_limit(srq.config.limit) {
+                    debug!("failed to rearm srq limit: {}", err);
+                }
+            }
+        })
+    }
+}
+
+impl Drop for SharedReceiveQueue {
+    fn drop(&mut self) {
+        // Destroyed before the replenisher goes, it owns the buffers still posted
+        let errno = unsafe { ibv_destroy_srq(self.as_ptr()) };
+        assert_eq!(errno, 0);
+        self.event_listener.cq.release(self.config.max_wr);
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+unsafe impl Send for SharedReceiveQueue {}
+
+unsafe impl Sync for SharedReceiveQueue {}
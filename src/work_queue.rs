@@ -0,0 +1,41 @@
+use futures::{ready, Future};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+type Acquire = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+/// Free entries of a send or receive queue. Posting more WRs than the queue holds fails with
+/// ENOMEM, so operations wait here for a completion to hand an entry back. Waiters are served
+/// in order, a batch waiting for many entries is not starved by single WRs
+pub fn work_queue_slots(capacity: u32) -> Arc<Semaphore> {
+    Arc::new(Semaphore::new(capacity as usize))
+}
+
+/// An acquisition of work queue slots for hand-written futures, it keeps its place in the
+/// queue between polls
+#[derive(Default)]
+pub struct SlotAcquire {
+    acquire: Option<Acquire>,
+}
+
+impl SlotAcquire {
+    /// Takes `n` slots at once, partial grants could deadlock two batches against each other
+    pub fn poll_acquire(
+        &mut self,
+        slots: &Arc<Semaphore>,
+        n: u32,
+        cx: &mut Context<'_>,
+    ) -> Poll<OwnedSemaphorePermit> {
+        let acquire = self
+            .acquire
+            .get_or_insert_with(|| Box::pin(slots.clone().acquire_many_owned(n)));
+        let permit = ready!(acquire.as_mut().poll(cx));
+        self.acquire = None;
+        // The slots of a QP are never closed
+        Poll::Ready(permit.unwrap())
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{
+    address_handle::AddressHandle,
     completion_queue::WorkRequestId,
     memory_region::{LocalMemoryRegion, RemoteMemoryRegion},
 };
@@ -13,7 +14,22 @@ pub struct SendWr {
 impl SendWr {
     fn new(lms: Vec<&LocalMemoryRegion>, wr_id: WorkRequestId) -> Self {
         assert!(!lms.is_empty());
-        let mut sges: Vec<ibv_sge> = lms.iter().map(|lm| (*lm).into()).collect();
+        Self::from_sges(lms.iter().map(|lm| (*lm).into()).collect(), wr_id)
+    }
+
+    /// The device copies `data` while posting, so it needs no lkey
+    fn new_inline(data: &[u8], wr_id: WorkRequestId) -> Self {
+        let sge = ibv_sge {
+            addr: data.as_ptr() as u64,
+            length: data.len() as u32,
+            lkey: 0,
+        };
+        let mut sr = Self::from_sges(vec![sge], wr_id);
+        sr.set_inline();
+        sr
+    }
+
+    fn from_sges(mut sges: Vec<ibv_sge>, wr_id: WorkRequestId) -> Self {
         let mut inner = unsafe { std::mem::zeroed::<ibv_send_wr>() };
         inner.next = std::ptr::null_mut();
         inner.wr_id = wr_id.into();
@@ -29,6 +45,33 @@ impl SendWr {
         sr
     }
 
+    pub fn new_send_with_imm(lms: Vec<&LocalMemoryRegion>, wr_id: WorkRequestId, imm: u32) -> Self {
+        let mut sr = Self::new_send(lms, wr_id);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_SEND_WITH_IMM;
+        sr.inner.__bindgen_anon_1.imm_data = imm.to_be();
+        sr
+    }
+
+    pub fn new_send_inline(data: &[u8], wr_id: WorkRequestId) -> Self {
+        let mut sr = Self::new_inline(data, wr_id);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_SEND;
+        sr.inner.send_flags |= ibv_send_flags::IBV_SEND_SIGNALED.0;
+        sr
+    }
+
+    pub fn new_send_to(
+        lms: Vec<&LocalMemoryRegion>,
+        wr_id: WorkRequestId,
+        ah: &AddressHandle,
+    ) -> Self {
+        let mut sr = Self::new_send(lms, wr_id);
+        let remote = ah.remote();
+        sr.inner.wr.ud.ah = ah.as_ptr();
+        sr.inner.wr.ud.remote_qpn = remote.qp_num;
+        sr.inner.wr.ud.remote_qkey = remote.qkey;
+        sr
+    }
+
     pub fn new_read(
         lms: Vec<&LocalMemoryRegion>,
         wr_id: WorkRequestId,
@@ -54,6 +97,88 @@ impl SendWr {
         sr.inner.wr.rdma.rkey = rm.rkey();
         sr
     }
+
+    pub fn new_write_inline(data: &[u8], wr_id: WorkRequestId, rm: &RemoteMemoryRegion) -> Self {
+        let mut sr = Self::new_inline(data, wr_id);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_RDMA_WRITE;
+        sr.inner.send_flags |= ibv_send_flags::IBV_SEND_SIGNALED.0;
+        sr.inner.wr.rdma.remote_addr = rm.as_ptr() as u64;
+        sr.inner.wr.rdma.rkey = rm.rkey();
+        sr
+    }
+
+    /// Touches no memory, zero length writes skip the rkey check
+    pub fn new_empty_write(wr_id: WorkRequestId) -> Self {
+        let mut sr = Self::from_sges(vec![], wr_id);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_RDMA_WRITE;
+        sr.inner.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        sr
+    }
+
+    pub fn new_compare_swap(
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+        rm: &RemoteMemoryRegion,
+        expected: u64,
+        new: u64,
+    ) -> Self {
+        let mut sr = Self::new(vec![lm], wr_id);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP;
+        sr.inner.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        sr.inner.wr.atomic.remote_addr = rm.as_ptr() as u64;
+        sr.inner.wr.atomic.rkey = rm.rkey();
+        sr.inner.wr.atomic.compare_add = expected;
+        sr.inner.wr.atomic.swap = new;
+        sr
+    }
+
+    pub fn new_fetch_add(
+        lm: &LocalMemoryRegion,
+        wr_id: WorkRequestId,
+        rm: &RemoteMemoryRegion,
+        delta: u64,
+    ) -> Self {
+        let mut sr = Self::new(vec![lm], wr_id);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD;
+        sr.inner.send_flags = ibv_send_flags::IBV_SEND_SIGNALED.0;
+        sr.inner.wr.atomic.remote_addr = rm.as_ptr() as u64;
+        sr.inner.wr.atomic.rkey = rm.rkey();
+        sr.inner.wr.atomic.compare_add = delta;
+        sr
+    }
+
+    /// Only valid for sends and writes no larger than the QP's `max_inline_data`
+    pub fn set_inline(&mut self) {
+        self.inner.send_flags |= ibv_send_flags::IBV_SEND_INLINE.0;
+    }
+
+    pub fn set_unsignaled(&mut self) {
+        self.inner.send_flags &= !ibv_send_flags::IBV_SEND_SIGNALED.0;
+    }
+
+    pub fn is_signaled(&self) -> bool {
+        self.inner.send_flags & ibv_send_flags::IBV_SEND_SIGNALED.0 != 0
+    }
+
+    pub fn wr_id(&self) -> WorkRequestId {
+        self.inner.wr_id.into()
+    }
+
+    pub fn byte_len(&self) -> u32 {
+        self.sges.iter().map(|sge| sge.length).sum()
+    }
+
+    pub fn new_write_with_imm(
+        lms: Vec<&LocalMemoryRegion>,
+        wr_id: WorkRequestId,
+        rm: &RemoteMemoryRegion,
+        imm: u32,
+    ) -> Self {
+        let mut sr = Self::new_write(lms, wr_id, rm);
+        sr.inner.opcode = ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM;
+        sr.inner.__bindgen_anon_1.imm_data = imm.to_be();
+        sr
+    }
 }
 
 impl AsRef<ibv_send_wr> for SendWr {
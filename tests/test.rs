@@ -1,27 +1,71 @@
-use async_rdma::{Rdma, RdmaListener};
+use async_rdma::{Rdma, RdmaBuilder, RdmaListener};
 use futures::Future;
+use std::time::Duration;
 use tokio::{io, net::ToSocketAddrs};
 
 type RdmaFn<R> = fn(Rdma) -> R;
+type BuilderFn = fn() -> RdmaBuilder;
+
+fn retry_builder() -> RdmaBuilder {
+    let mut builder = RdmaBuilder::default();
+    builder.set_connect_retry(10, Duration::from_millis(100), Duration::from_secs(1));
+    builder.set_connect_deadline(Duration::from_secs(10));
+    builder
+}
 
 #[tokio::main]
 async fn server<A: ToSocketAddrs, R: Future<Output = Result<(), io::Error>>>(
     addr: A,
+    builder: BuilderFn,
     f: RdmaFn<R>,
 ) -> io::Result<()> {
-    let rdma = RdmaListener::bind(addr).await?.accept().await?;
-    f(rdma).await
+    let listener = RdmaListener::bind_with_builder(addr, &builder()).await?;
+    f(listener.accept().await?).await
 }
 
 #[tokio::main]
 async fn client<A: ToSocketAddrs, R: Future<Output = Result<(), io::Error>>>(
+    addr: A,
+    builder: BuilderFn,
+    f: RdmaFn<R>,
+) -> io::Result<()> {
+    let rdma = builder().connect(addr).await?;
+    f(rdma).await
+}
+
+#[tokio::main]
+async fn cm_server<A: ToSocketAddrs, R: Future<Output = Result<(), io::Error>>>(
+    addr: A,
+    f: RdmaFn<R>,
+) -> io::Result<()> {
+    let rdma = RdmaListener::cm_bind(addr).await?.accept().await?;
+    f(rdma).await
+}
+
+#[tokio::main]
+async fn cm_client<A: ToSocketAddrs, R: Future<Output = Result<(), io::Error>>>(
     addr: A,
     f: RdmaFn<R>,
 ) -> io::Result<()> {
-    let rdma = Rdma::connect(addr).await?;
+    let rdma = retry_builder().cm_connect(addr).await?;
     f(rdma).await
 }
 
+fn test_cm_server_client<
+    A: 'static + ToSocketAddrs + Send + Copy,
+    SR: Future<Output = Result<(), io::Error>> + 'static,
+    CR: Future<Output = Result<(), io::Error>> + 'static,
+>(
+    addr: A,
+    s: RdmaFn<SR>,
+    c: RdmaFn<CR>,
+) -> io::Result<()> {
+    let server = std::thread::spawn(move || cm_server(addr, s));
+    let client = std::thread::spawn(move || cm_client(addr, c));
+    client.join().unwrap()?;
+    server.join().unwrap()
+}
+
 fn test_server_client<
     A: 'static + ToSocketAddrs + Send + Copy,
     SR: Future<Output = Result<(), io::Error>> + 'static,
@@ -31,9 +75,22 @@ fn test_server_client<
     s: RdmaFn<SR>,
     c: RdmaFn<CR>,
 ) -> io::Result<()> {
-    let server = std::thread::spawn(move || server(addr, s));
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    let client = std::thread::spawn(move || client(addr, c));
+    test_server_client_with(addr, RdmaBuilder::default, retry_builder, s, c)
+}
+
+fn test_server_client_with<
+    A: 'static + ToSocketAddrs + Send + Copy,
+    SR: Future<Output = Result<(), io::Error>> + 'static,
+    CR: Future<Output = Result<(), io::Error>> + 'static,
+>(
+    addr: A,
+    server_builder: BuilderFn,
+    client_builder: BuilderFn,
+    s: RdmaFn<SR>,
+    c: RdmaFn<CR>,
+) -> io::Result<()> {
+    let server = std::thread::spawn(move || server(addr, server_builder, s));
+    let client = std::thread::spawn(move || client(addr, client_builder, c));
     client.join().unwrap()?;
     server.join().unwrap()
 }
@@ -73,7 +130,7 @@ mod test2 {
         for _ in 0..10 {
             let rdma_clone = rdma.clone();
             handles.push(tokio::spawn(async move {
-                let lm = rdma_clone.receive().await;
+                let lm = rdma_clone.receive().await.unwrap();
                 assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 5);
                 assert_eq!(lm.length(), 4);
             }));
@@ -106,3 +163,747 @@ mod test2 {
         test_server_client("127.0.0.1:8001", server, client)
     }
 }
+
+mod test3 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 3);
+        let mr = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(mr.as_ptr() as *mut [i32; 2]) }, [3, 4]);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        unsafe { *(lm.as_ptr() as *mut i32) = 3 };
+        rdma.send(&lm).await?;
+        // Reads and writes over the QP the cm connected
+        let rm = rdma.alloc_remote_mr(Layout::new::<[i32; 2]>()).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<[i32; 2]>())?;
+        unsafe { *(lm.as_ptr() as *mut [i32; 2]) = [3, 4] };
+        rdma.write(&lm, &rm).await?;
+        let mut back = rdma.alloc_local_mr(Layout::new::<[i32; 2]>())?;
+        rdma.read(&mut back, &rm).await?;
+        assert_eq!(unsafe { *(back.as_ptr() as *mut [i32; 2]) }, [3, 4]);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_cm_server_client("127.0.0.1:8002", server, client)
+    }
+}
+
+mod test4 {
+    use crate::*;
+    use std::alloc::Layout;
+    use tokio::net::{UnixListener, UnixStream};
+
+    const PATH: &str = "/tmp/async-rdma-test4.sock";
+
+    #[tokio::main]
+    async fn server(listener: std::os::unix::net::UnixListener) -> io::Result<()> {
+        let listener = UnixListener::from_std(listener)?;
+        let listener = RdmaListener::with_exchanger(listener, &RdmaBuilder::default())?;
+        let rdma = listener.accept().await?;
+        let lm = rdma.receive().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 5);
+        Ok(())
+    }
+
+    #[tokio::main]
+    async fn client() -> io::Result<()> {
+        let rdma = Rdma::connect_with(UnixStream::connect(PATH).await?).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>()).unwrap();
+        unsafe { *(lm.as_ptr() as *mut i32) = 5 };
+        rdma.send(&lm).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        // Bound before the client starts, so it never finds the socket missing
+        let _ = std::fs::remove_file(PATH);
+        let listener = std::os::unix::net::UnixListener::bind(PATH)?;
+        listener.set_nonblocking(true)?;
+        let server = std::thread::spawn(move || server(listener));
+        let client = std::thread::spawn(client);
+        client.join().unwrap()?;
+        server.join().unwrap()
+    }
+}
+
+mod test5 {
+    use crate::*;
+    use std::alloc::Layout;
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 5);
+        rdma.remote_closed().await;
+        assert!(rdma.is_remote_closed());
+        assert!(rdma.receive().await.is_err());
+        rdma.close().await
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>()).unwrap();
+        unsafe { *(lm.as_ptr() as *mut i32) = 5 };
+        rdma.send(&lm).await?;
+        rdma.close().await?;
+        assert!(rdma.is_closed());
+        assert!(rdma.send(&lm).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8003", server, client)
+    }
+}
+
+mod test6 {
+    use crate::*;
+    use async_rdma::AsyncEvent;
+    use futures::StreamExt;
+    use std::alloc::Layout;
+
+    async fn no_fatal_event(rdma: &Rdma) {
+        let mut events = rdma.async_events();
+        let event = tokio::time::timeout(Duration::from_millis(100), events.next()).await;
+        assert!(!matches!(
+            event,
+            Ok(Some(AsyncEvent::QpFatal { .. } | AsyncEvent::DeviceFatal))
+        ));
+    }
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 6);
+        no_fatal_event(&rdma).await;
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        no_fatal_event(&rdma).await;
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>()).unwrap();
+        unsafe { *(lm.as_ptr() as *mut i32) = 6 };
+        rdma.send(&lm).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8004", server, client)
+    }
+}
+
+mod test7 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 7);
+        assert_eq!(rdma.qp_count(), 2);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        assert_eq!(rdma.open_qp().await?, 1);
+        assert_eq!(rdma.qp_count(), 2);
+        let rm = rdma.alloc_remote_mr(Layout::new::<i32>()).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        unsafe { *(lm.as_ptr() as *mut i32) = 7 };
+        rdma.write_on(1, &lm, &rm).await?;
+        assert!(rdma.write_on(2, &lm, &rm).await.is_err());
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8005", server, client)
+    }
+}
+
+mod test8 {
+    use crate::*;
+    use async_rdma::devices;
+
+    #[tokio::test]
+    async fn test() -> io::Result<()> {
+        let mut builder = RdmaBuilder::default();
+        builder.set_max_send_wr(u32::MAX);
+        let err = builder.build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("the device supports"));
+        let mut builder = RdmaBuilder::default();
+        builder.set_retry_cnt(8);
+        let err = builder.build().err().unwrap();
+        assert!(err.to_string().contains("the protocol allows"));
+        let mut builder = RdmaBuilder::default();
+        builder.set_max_inline_data(1 << 20);
+        let err = builder.build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let attr = devices()?.remove(0).attr;
+        if u64::from(attr.max_qp_wr) * 2 > u64::from(attr.max_cqe) {
+            let mut builder = RdmaBuilder::default();
+            builder.set_max_send_wr(attr.max_qp_wr);
+            builder.set_max_recv_wr(attr.max_qp_wr);
+            let err = builder.build().err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        // More WRs than the initial 16 CQ entries, the CQ grows to hold them
+        let mut builder = RdmaBuilder::default();
+        builder.set_max_send_wr(32);
+        builder.set_sq_sig_all(true);
+        builder.build()?;
+        Ok(())
+    }
+}
+
+mod test9 {
+    use crate::*;
+    use async_rdma::QueuePairState;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        // Once this arrives the agent keeps receives posted on QP 0
+        rdma.send(&rdma.alloc_local_mr(Layout::new::<i32>())?)
+            .await?;
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 9);
+        assert_eq!(rdma.query_qp_state(1)?, QueuePairState::Rts);
+        Ok(())
+    }
+
+    fn client_builder() -> RdmaBuilder {
+        let mut builder = retry_builder();
+        // A send without a receive posted for it fails at once and moves the QP to ERR
+        builder.set_rnr_retry(0);
+        builder
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let _ = rdma.receive().await?;
+        assert_eq!(rdma.query_state()?, QueuePairState::Rts);
+        assert_eq!(rdma.open_qp().await?, 1);
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        // Batches go over QP 1, the peer never posts receives there
+        assert!(rdma.batch()?.send(&lm).post().await.is_err());
+        assert_eq!(rdma.query_qp_state(1)?, QueuePairState::Error);
+        rdma.recover_qp(1).await?;
+        assert_eq!(rdma.query_qp_state(1)?, QueuePairState::Rts);
+        assert!(rdma.recover_qp(0).await.is_err());
+        let rm = rdma.alloc_remote_mr(Layout::new::<i32>()).await?;
+        unsafe { *(lm.as_ptr() as *mut i32) = 9 };
+        rdma.write_on(1, &lm, &rm).await?;
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client_with(
+            "127.0.0.1:8006",
+            RdmaBuilder::default,
+            client_builder,
+            server,
+            client,
+        )
+    }
+}
+
+mod test10 {
+    use crate::*;
+    use async_rdma::SrqConfig;
+    use std::alloc::Layout;
+
+    fn srq_builder() -> RdmaBuilder {
+        let mut builder = retry_builder();
+        builder.set_srq(SrqConfig::default());
+        builder
+    }
+
+    #[tokio::main]
+    async fn server() -> io::Result<()> {
+        let listener = RdmaListener::bind_with_builder("127.0.0.1:8007", &srq_builder()).await?;
+        let first = listener.accept().await?;
+        let second = listener.accept().await?;
+        let srq = first.srq().unwrap();
+        assert_eq!(srq.handle(), second.srq().unwrap().handle());
+        for rdma in [first, second] {
+            let lm = rdma.receive().await?;
+            assert_eq!(lm.as_slice(), &[10u8; 8]);
+        }
+        Ok(())
+    }
+
+    #[tokio::main]
+    async fn client() -> io::Result<()> {
+        let rdma = srq_builder().connect("127.0.0.1:8007").await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<[u8; 8]>())?;
+        unsafe { *(lm.as_ptr() as *mut [u8; 8]) = [10u8; 8] };
+        rdma.send(&lm).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        let server = std::thread::spawn(server);
+        let clients = [std::thread::spawn(client), std::thread::spawn(client)];
+        for client in clients {
+            client.join().unwrap()?;
+        }
+        server.join().unwrap()
+    }
+}
+
+mod test11 {
+    use crate::*;
+    use async_rdma::GRH_LEN;
+    use std::alloc::Layout;
+
+    #[tokio::test]
+    async fn test() -> io::Result<()> {
+        let builder = RdmaBuilder::default();
+        let server = builder.build_datagram()?;
+        let client = builder.build_datagram()?;
+        let buf = server.alloc_local_mr(Layout::new::<[u8; GRH_LEN + 8]>())?;
+        // UD drops datagrams that find no receive posted
+        let mut received = Box::pin(server.recv_from(&buf));
+        assert!(futures::poll!(&mut received).is_pending());
+        let ah = client.create_ah(server.endpoint())?;
+        let lm = client.alloc_local_mr(Layout::new::<[u8; 8]>())?;
+        unsafe { *(lm.as_ptr() as *mut [u8; 8]) = [11u8; 8] };
+        client.send_to(&ah, &lm).await?;
+        let (len, source) = received.await?;
+        assert_eq!(len, 8);
+        assert_eq!(source.qp_num, client.endpoint().qp_num);
+        assert_eq!(&buf.as_slice()[GRH_LEN..], &[11u8; 8]);
+        Ok(())
+    }
+}
+
+mod test12 {
+    use crate::*;
+    use async_rdma::QueuePairType;
+    use std::{alloc::Layout, sync::Arc};
+
+    fn uc_builder() -> RdmaBuilder {
+        let mut builder = retry_builder();
+        builder.set_qp_type(QueuePairType::Uc);
+        let err = builder.build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        builder.set_request_timeout(Duration::from_secs(5));
+        builder
+    }
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 12);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<i32>()).await?;
+        let mut lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        unsafe { *(lm.as_ptr() as *mut i32) = 12 };
+        rdma.write(&lm, &rm).await?;
+        let err = rdma.read(&mut lm, &rm).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client_with("127.0.0.1:8009", uc_builder, uc_builder, server, client)
+    }
+}
+
+mod test13 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        assert_eq!(rdma.receive_write_imm().await?, (16, 4));
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 13);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<i32>()).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        unsafe { *(lm.as_ptr() as *mut i32) = 13 };
+        rdma.write_with_imm(&lm, &rm, 16).await?;
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8010", server, client)
+    }
+}
+
+mod test14 {
+    use crate::*;
+    use std::alloc::Layout;
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let (lm, imm) = rdma.receive_with_imm().await?;
+        assert_eq!(imm, Some(7));
+        assert_eq!(lm.as_slice(), &[14u8; 4]);
+        let (_, imm) = rdma.receive_with_imm().await?;
+        assert_eq!(imm, None);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.alloc_local_mr(Layout::new::<[u8; 4]>())?;
+        unsafe { *(lm.as_ptr() as *mut [u8; 4]) = [14u8; 4] };
+        rdma.send_with_imm(&lm, 7).await?;
+        rdma.send(&lm).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8011", server, client)
+    }
+}
+
+mod test15 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut u64) }, 9);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<u64>()).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<u64>())?;
+        unsafe { *(lm.as_ptr() as *mut u64) = 0 };
+        rdma.write(&lm, &rm).await?;
+        assert_eq!(rdma.fetch_and_add(&rm, 5).await?, 0);
+        assert_eq!(rdma.compare_and_swap(&rm, 4, 7).await?, 5);
+        assert_eq!(rdma.compare_and_swap(&rm, 5, 9).await?, 5);
+        let err = rdma.fetch_and_add(&rm.slice(1..8)?, 1).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8012", server, client)
+    }
+}
+
+mod test16 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        assert_eq!(rdma.receive().await?.as_slice(), b"inline");
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut [u8; 4]) }, [16u8; 4]);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        assert!(rdma.inline_threshold() >= 64);
+        rdma.send_inline(b"inline").await?;
+        let rm = rdma.alloc_remote_mr(Layout::new::<[u8; 4]>()).await?;
+        rdma.write_inline(&[16u8; 4], &rm).await?;
+        let big = vec![0u8; rdma.inline_threshold() + 1];
+        let err = rdma.write_inline(&big, &rm).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8013", server, client)
+    }
+}
+
+mod test17 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut [i32; 4]) }, [0, 1, 2, 3]);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<[i32; 4]>()).await?;
+        let lms = (0..4)
+            .map(|i| {
+                let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+                unsafe { *(lm.as_ptr() as *mut i32) = i };
+                Ok(lm)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let rms = (0..4)
+            .map(|i| rm.slice(i * 4..i * 4 + 4))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut batch = rdma.batch()?;
+        for (lm, rm) in lms.iter().zip(&rms) {
+            batch = batch.write(lm, rm);
+        }
+        assert_eq!(batch.len(), 4);
+        batch.post().await?;
+        let mut back = rdma.alloc_local_mr(Layout::new::<[i32; 4]>())?;
+        rdma.batch()?.read(&mut back, &rm).post().await?;
+        assert_eq!(unsafe { *(back.as_ptr() as *mut [i32; 4]) }, [0, 1, 2, 3]);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8014", server, client)
+    }
+}
+
+mod test18 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    fn builder() -> RdmaBuilder {
+        let mut builder = retry_builder();
+        builder.set_signal_interval(4);
+        builder
+    }
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(
+            unsafe { *(lm.as_ptr() as *mut [i32; 6]) },
+            [0, 1, 2, 3, 4, 5]
+        );
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<[i32; 6]>()).await?;
+        let mut lms = vec![];
+        let mut rms = vec![];
+        for i in 0..6 {
+            let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+            unsafe { *(lm.as_ptr() as *mut i32) = i as i32 };
+            lms.push(lm);
+            rms.push(rm.slice(i * 4..i * 4 + 4)?);
+        }
+        // Awaited alone every write is signaled, none needs an empty write behind it
+        let (wrs, cqes) = (rdma.send_wrs_posted(), rdma.completions_polled());
+        for (lm, rm) in lms.iter().zip(&rms) {
+            rdma.write(lm, rm).await?;
+        }
+        assert_eq!(rdma.send_wrs_posted() - wrs, 6);
+        assert_eq!(rdma.completions_polled() - cqes, 6);
+        // The first write is signaled, the fifth covers the three in front of it and the last
+        // one needs an empty write
+        let (wrs, cqes) = (rdma.send_wrs_posted(), rdma.completions_polled());
+        let writes = lms.iter().zip(&rms).map(|(lm, rm)| rdma.write(lm, rm));
+        for ans in futures::future::join_all(writes).await {
+            ans?;
+        }
+        assert_eq!(rdma.send_wrs_posted() - wrs, 7);
+        assert_eq!(rdma.completions_polled() - cqes, 3);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client_with("127.0.0.1:8015", builder, builder, server, client)
+    }
+}
+
+mod test19 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 19);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rdma = Arc::new(rdma);
+        let rm = Arc::new(rdma.alloc_remote_mr(Layout::new::<i32>()).await?);
+        let lm = Arc::new(rdma.alloc_local_mr(Layout::new::<i32>())?);
+        unsafe { *(lm.as_ptr() as *mut i32) = 19 };
+        // Far more than max_send_wr, the excess waits for free slots
+        let tasks = (0..100).map(|_| {
+            let (rdma, lm, rm) = (rdma.clone(), lm.clone(), rm.clone());
+            tokio::spawn(async move { rdma.write(&lm, &rm).await })
+        });
+        for task in futures::future::join_all(tasks).await {
+            task.unwrap()?;
+        }
+        rdma.send_mr(rm).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8016", server, client)
+    }
+}
+
+mod test20 {
+    use crate::*;
+    use async_rdma::OperationTimeout;
+    use std::alloc::Layout;
+    use tokio::sync::Notify;
+
+    static CLOSED: Notify = Notify::const_new();
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let _ = rdma.receive().await?;
+        // A QP in the error state drops incoming requests, the client's read goes unanswered
+        rdma.close().await?;
+        CLOSED.notify_one();
+        Ok(())
+    }
+
+    fn client_builder() -> RdmaBuilder {
+        let mut builder = retry_builder();
+        // The unanswered read fails after two 67ms ack timeouts
+        builder.set_qp_timeout(14);
+        builder.set_retry_cnt(1);
+        builder
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<i32>()).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        rdma.send(&lm).await?;
+        CLOSED.notified().await;
+        let (ans, lm) = rdma.read_timeout(lm, &rm, Duration::from_millis(10)).await;
+        let err = ans.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<OperationTimeout>()),
+            Some(&OperationTimeout::InFlight)
+        );
+        assert!(!lm.is_ready());
+        assert_eq!(rdma.abandoned_in_flight(), 1);
+        // The buffer comes back with the error completion of the read
+        let _lm = tokio::time::timeout(Duration::from_secs(5), lm).await??;
+        assert_eq!(rdma.abandoned_in_flight(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client_with(
+            "127.0.0.1:8017",
+            RdmaBuilder::default,
+            client_builder,
+            server,
+            client,
+        )
+    }
+}
+
+mod test21 {
+    use crate::*;
+    use std::{alloc::Layout, sync::Arc};
+
+    async fn server(rdma: Rdma) -> io::Result<()> {
+        let lm = rdma.receive_local_mr().await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 21);
+        Ok(())
+    }
+
+    async fn client(rdma: Rdma) -> io::Result<()> {
+        let rm = rdma.alloc_remote_mr(Layout::new::<i32>()).await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        unsafe { *(lm.as_ptr() as *mut i32) = 21 };
+        let (ans, lm) = rdma.write_owned(lm, &rm).await;
+        ans?;
+        let lm = lm.await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 21);
+        // The read is posted and then dropped, its buffer stays with the WR until it completes
+        let buf = rdma.alloc_local_mr(Layout::new::<i32>())?;
+        tokio::select! {
+            biased;
+            _ = rdma.read_owned(buf, &rm) => panic!("read completed within its first poll"),
+            _ = futures::future::ready(()) => (),
+        }
+        assert_eq!(rdma.abandoned_in_flight(), 1);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while rdma.abandoned_in_flight() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let (ans, lm) = rdma.read_owned(lm, &rm).await;
+        ans?;
+        let lm = lm.await?;
+        assert_eq!(unsafe { *(lm.as_ptr() as *mut i32) }, 21);
+        rdma.send_mr(Arc::new(rm)).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        test_server_client("127.0.0.1:8018", server, client)
+    }
+}
+
+mod test22 {
+    use crate::*;
+    use async_rdma::SrqConfig;
+    use futures::StreamExt;
+    use std::alloc::Layout;
+
+    fn srq_builder() -> RdmaBuilder {
+        let mut builder = retry_builder();
+        builder.set_srq(SrqConfig::default());
+        builder
+    }
+
+    #[tokio::main]
+    async fn server() -> io::Result<()> {
+        let listener = RdmaListener::bind_with_builder("127.0.0.1:8019", &srq_builder()).await?;
+        let rdmas = listener
+            .incoming()
+            .take(2)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()?;
+        // Connections of one listener share its device resources, the SRQ among them
+        let srq = rdmas[0].srq().unwrap();
+        assert_eq!(srq.handle(), rdmas[1].srq().unwrap().handle());
+        for rdma in rdmas {
+            let lm = rdma.receive().await?;
+            assert_eq!(lm.as_slice(), &[22u8; 8]);
+        }
+        Ok(())
+    }
+
+    #[tokio::main]
+    async fn client() -> io::Result<()> {
+        let rdma = srq_builder().connect("127.0.0.1:8019").await?;
+        let lm = rdma.alloc_local_mr(Layout::new::<[u8; 8]>())?;
+        unsafe { *(lm.as_ptr() as *mut [u8; 8]) = [22u8; 8] };
+        rdma.send(&lm).await
+    }
+
+    #[test]
+    fn test() -> io::Result<()> {
+        let server = std::thread::spawn(server);
+        let clients = [std::thread::spawn(client), std::thread::spawn(client)];
+        for client in clients {
+            client.join().unwrap()?;
+        }
+        server.join().unwrap()
+    }
+}